@@ -0,0 +1,70 @@
+extern crate face_generator;
+
+use std::collections::HashMap;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use face_generator::weights::{Combine, Weights};
+
+const FACES: u64 = 200;
+const ROUNDS: usize = 20;
+
+// Whole faces per second, which is what weight lookups cost in practice,
+// followed by lookups per second for the paths those faces look up, matched
+// one regex at a time and through the compiled RegexSet
+fn main() {
+    let mut generator = face_generator::Generator::new(&Path::new("assets"));
+    let attributes = HashMap::new();
+    let mut paths = Vec::new();
+    for seed in 0..FACES {
+        if let Ok((_, _, explanation)) = generator.explain(seed, &attributes) {
+            paths.extend(explanation.lookups.into_iter().map(|l| l.path));
+        }
+    }
+    println!("{} lookups from {} faces", paths.len(), FACES);
+
+    let generate = time(|| {
+        for seed in 0..FACES {
+            black_box(generator.generate_with_seed(seed, &attributes).is_ok());
+        }
+    });
+    println!("{:<12} {:>12.0} faces/s", "generate", FACES as f64 / seconds(generate));
+
+    let weights = Weights::from_file(Path::new("assets/probabilities")).unwrap();
+    let scan = time(|| {
+        for path in &paths {
+            let first = weights.rules().iter().find(|r| r.combine == Combine::Override && r.regex.is_match(path));
+            black_box(first.map(|r| r.weight));
+        }
+    });
+    report("linear scan", paths.len(), scan);
+
+    let set = time(|| {
+        for path in &paths {
+            black_box(weights.rules_for_path(path).len());
+        }
+    });
+    report("regex set", paths.len(), set);
+}
+
+fn time<F: FnMut()>(mut f: F) -> Duration {
+    f();
+    let start = Instant::now();
+    for _ in 0..ROUNDS {
+        f();
+    }
+    start.elapsed() / ROUNDS as u32
+}
+
+fn report(name: &str, lookups: usize, elapsed: Duration) {
+    println!("{:<12} {:>12.0} lookups/s", name, lookups as f64 / seconds(elapsed));
+}
+
+fn seconds(duration: Duration) -> f64 {
+    duration.as_secs() as f64 + f64::from(duration.subsec_nanos()) * 1e-9
+}
+
+// Keeps the optimizer from dropping a result nobody reads
+fn black_box<T: Copy>(value: T) -> T {
+    unsafe { std::ptr::read_volatile(&value) }
+}
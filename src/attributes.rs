@@ -0,0 +1,83 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use rand::prelude::*;
+
+use super::error::GenerateError;
+
+// The axes every face is placed on before any template is chosen, in the
+// order they appear in the weight paths, e.g. `:species:elf:age:adult:sex:female`.
+pub struct AttributeAxes {
+    axes: Vec<(String, BTreeMap<String, f32>)>,
+}
+
+impl AttributeAxes {
+    pub fn from_file(path: &Path) -> Self {
+        let file = File::open(path).unwrap();
+        let reader = BufReader::new(file);
+        let axes = serde_json::from_reader(reader).unwrap();
+        Self { axes }
+    }
+
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.axes.iter().map(|(name, _)| name.as_str())
+    }
+
+    pub fn values(&self, axis: &str) -> Option<&BTreeMap<String, f32>> {
+        self.axes.iter().find(|(name, _)| name == axis).map(|(_, values)| values)
+    }
+
+    pub fn choose<R: Rng>(&self, attributes: &HashMap<String, HashSet<String>>, rng: &mut R) -> Result<BTreeMap<String, String>, GenerateError> {
+        let mut chosen = BTreeMap::new();
+        for (axis, values) in &self.axes {
+            let possible: Vec<(&String, &f32)> = match attributes.get(axis) {
+                Some(selected) => values.iter().filter(|(v, _)| selected.contains(*v)).collect(),
+                None => values.iter().collect(),
+            };
+            let value = match possible.choose_weighted(rng, |v| *v.1) {
+                Ok((value, _)) => value,
+                Err(_) => {
+                    let constraint = attributes.get(axis).map(|selected| {
+                        let mut selected: Vec<&str> = selected.iter().map(|s| s.as_str()).collect();
+                        selected.sort();
+                        format!("{} in {{{}}}", axis, selected.join(","))
+                    });
+                    return Err(GenerateError::Conflict {
+                        constraint,
+                        path: format!("{}:{}", self.path(&chosen), axis),
+                        rules: possible.iter().map(|(v, w)| format!("{}:{}|{} (attributes.json)", axis, v, w)).collect(),
+                    });
+                }
+            };
+            chosen.insert(axis.to_string(), value.to_string());
+        }
+        Ok(chosen)
+    }
+
+    // Every combination `choose` can produce without restrictions, with its probability
+    pub fn combinations(&self) -> Vec<(BTreeMap<String, String>, f64)> {
+        let mut combinations = vec![(BTreeMap::new(), 1.0)];
+        for (axis, values) in &self.axes {
+            let total: f32 = values.values().sum();
+            let mut extended = Vec::new();
+            for (chosen, probability) in &combinations {
+                for (value, weight) in values.iter().filter(|(_, w)| **w > 0.0) {
+                    let mut chosen: BTreeMap<String, String> = chosen.clone();
+                    chosen.insert(axis.to_string(), value.to_string());
+                    extended.push((chosen, probability * f64::from(weight / total)));
+                }
+            }
+            combinations = extended;
+        }
+        combinations
+    }
+
+    pub fn path(&self, chosen: &BTreeMap<String, String>) -> String {
+        self.axes
+            .iter()
+            .filter_map(|(axis, _)| chosen.get(axis).map(|value| format!(":{}:{}", axis, value)))
+            .collect()
+    }
+}
@@ -0,0 +1,20 @@
+extern crate face_generator;
+
+use std::env;
+use std::path::Path;
+
+use face_generator::weights::Weights;
+
+// Converts a legacy `regex|weight` probabilities file into the structured
+// `.jsonl` rules format on stdout, e.g.
+//   convert_probabilities assets/probabilities > assets/probabilities.jsonl
+fn main() {
+    let path = env::args().nth(1).unwrap_or_else(|| "assets/probabilities".to_string());
+    match Weights::from_file(Path::new(&path)) {
+        Ok(weights) => print!("{}", weights.to_json_lines()),
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+    }
+}
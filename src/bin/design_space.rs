@@ -0,0 +1,36 @@
+extern crate face_generator;
+
+use std::env;
+use std::path::Path;
+
+use face_generator::design_space;
+
+// Reports how many distinct faces the assets can produce and which parts can
+// never appear. `design_space --enumerate N` lists the first N faces instead.
+fn main() {
+    let generator = face_generator::Generator::new(&Path::new("assets"));
+    let args: Vec<String> = env::args().collect();
+
+    if args.len() == 3 && args[1] == "--enumerate" {
+        let limit = args[2].parse().expect("--enumerate expects a number of faces");
+        for (decisions, probability) in design_space::enumerate(&generator, limit) {
+            let decisions: Vec<String> = decisions.iter().map(|(k, v)| format!("{}:{}", k, v)).collect();
+            println!("{:.3e} {}", probability, decisions.join(" "));
+        }
+        return;
+    }
+
+    let space = design_space::measure(&generator);
+    println!("{} distinct faces, not counting colors and jitter", space.count);
+    println!("{:.2}% of generations fail", space.failure_probability * 100.0);
+    println!();
+    println!("slot probabilities:");
+    for (slot, probability) in &space.marginals {
+        println!("  {:<40} {:>8.4}%", slot, probability * 100.0);
+    }
+    println!();
+    println!("dead slots:");
+    for slot in &space.dead {
+        println!("  {}", slot);
+    }
+}
@@ -0,0 +1,34 @@
+extern crate face_generator;
+extern crate rand;
+
+use std::collections::HashMap;
+use std::env;
+use std::path::Path;
+
+use rand::Rng;
+
+// Generates a face and prints every weight lookup behind it. Pass a face code
+// to explain that face, otherwise a random one is generated.
+fn main() {
+    let mut generator = face_generator::Generator::new(&Path::new("assets"));
+    let (code, explained) = match env::args().nth(1) {
+        Some(code) => {
+            let explained = generator.explain_code(&code);
+            (code, explained)
+        }
+        None => {
+            let seed = rand::thread_rng().gen();
+            (generator.face_code(seed, &HashMap::new()), generator.explain(seed, &HashMap::new()))
+        }
+    };
+
+    match explained {
+        Ok((_, recipe, explanation)) => {
+            println!("face code {}", code);
+            println!("{}", recipe.to_json());
+            println!();
+            print!("{}", explanation);
+        }
+        Err(e) => eprintln!("{}", e),
+    }
+}
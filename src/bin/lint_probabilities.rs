@@ -0,0 +1,23 @@
+extern crate face_generator;
+
+use std::path::Path;
+
+use face_generator::lint;
+
+// Checks the probability rules in assets/ for rules that never fire, names that
+// don't exist in the assets and probabilities above 1, lists template classes
+// palette.json doesn't color and palette colors no template uses.
+fn main() {
+    let generator = face_generator::Generator::new(&Path::new("assets"));
+    let problems = lint::lint(&generator);
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    let palette_problems = lint::palette_problems(&generator);
+    for problem in &palette_problems {
+        println!("palette.json: {}", problem);
+    }
+    if !problems.is_empty() || !palette_problems.is_empty() {
+        std::process::exit(1);
+    }
+}
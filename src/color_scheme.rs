@@ -1,14 +1,19 @@
 use rand::prelude::*;
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::fs::File;
 use std::io::BufReader;
 use std::path::Path;
-use std::iter::FromIterator;
 
-use palette::{LinSrgb, LinSrgba, Hsla, Hsl, Color, Shade, Saturate, Pixel, Blend};
+use palette::{LinSrgb, LinSrgba, Hsla, Hsl, Lch, Srgb, Color, Shade, Saturate, Pixel, Blend, Mix};
 
 
 use super::Palette;
+use super::constraints::Constraints;
+use super::error::GenerateError;
+use super::explain::Lookup;
+use super::recipe::PaletteChoice;
+use super::weights::{Weight, Weights};
 
 #[derive(Serialize, Deserialize, Copy, Clone)]
 #[serde(untagged)]
@@ -22,114 +27,972 @@ enum ColorComponent {
 enum ColorFunction {
     HSL(ColorComponent, ColorComponent, ColorComponent),
     SkinModel((f32, f32), (f32, f32, f32), (f32, f32, f32)),
+    // `{"lch": [l, c, h]}`, CIE LCh with lightness 0-100, chroma from 0 to
+    // about 130 and hue from 0 to 1 like HSL
+    Lch { lch: (ColorComponent, ColorComponent, ColorComponent) },
+    // `{"oklab": [l, a, b]}`, lightness 0-1 with a and b between about -0.4
+    // and 0.4
+    Oklab { oklab: (ColorComponent, ColorComponent, ColorComponent) },
 }
 #[derive(Serialize, Deserialize)]
 #[serde(untagged)]
 enum RawPaletteVarient {
     Color(ColorFunction),
     ColorWithSubchoice(ColorFunction, HashMap<String, Vec<String>>),
+    // `{"color": ..., "weight": 2.0, "subchoices": {...}}`, drawn `weight`
+    // times as often as a variant without one
+    Weighted {
+        color: ColorFunction,
+        #[serde(default = "default_weight")]
+        weight: f32,
+        #[serde(default)]
+        subchoices: HashMap<String, Vec<String>>,
+    },
 }
-type RawPalette = Vec<(String, HashMap<String, HashMap<String, HashMap<String, RawPaletteVarient>>>)>;
 
-fn sample_component(p: &ColorComponent) -> f32 {
+fn default_weight() -> f32 {
+    1.0
+}
+
+impl RawPaletteVarient {
+    fn color_function(&self) -> &ColorFunction {
+        match self {
+            RawPaletteVarient::Color(func) | RawPaletteVarient::ColorWithSubchoice(func, _) => func,
+            RawPaletteVarient::Weighted { color, .. } => color,
+        }
+    }
+
+    fn sub_choices(&self) -> Option<&HashMap<String, Vec<String>>> {
+        match self {
+            RawPaletteVarient::Color(_) => None,
+            RawPaletteVarient::ColorWithSubchoice(_, sub_choices) => Some(sub_choices),
+            RawPaletteVarient::Weighted { subchoices, .. } => Some(subchoices),
+        }
+    }
+
+    fn weight(&self) -> f32 {
+        match self {
+            RawPaletteVarient::Weighted { weight, .. } => *weight,
+            _ => 1.0,
+        }
+    }
+}
+impl ColorFunction {
+    // HSL and LCh hues are fractions of a turn, a hue in degrees would wrap
+    // around silently
+    fn check_hue(&self) -> Result<(), String> {
+        let hue = match self {
+            ColorFunction::HSL(hue, _, _) | ColorFunction::Lch { lch: (_, _, hue) } => *hue,
+            _ => return Ok(()),
+        };
+        let (start, end) = match hue {
+            ColorComponent::Range(start, end) => (start, end),
+            ColorComponent::Constant(value) => (value, value),
+        };
+        if start < 0.0 || end > 1.0 {
+            return Err(format!("hue {}..{} isn't between 0 and 1", start, end));
+        }
+        Ok(())
+    }
+}
+
+type PaletteSection = HashMap<String, HashMap<String, HashMap<String, RawPaletteVarient>>>;
+
+// Colors derived from a section's color, used through classes like
+// `skin_color_shadow`, e.g. `"shadow": {"darken": 0.3, "hue_shift": -0.03}`.
+// Amounts are the fraction of the way towards black, white, grey or full
+// saturation and hue shifts are fractions of a turn between -1 and 1 like
+// HSL hues. A tone with a `color` is sampled on its own instead.
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(deny_unknown_fields)]
+struct ToneSpec {
+    #[serde(default)]
+    darken: f32,
+    #[serde(default)]
+    lighten: f32,
+    #[serde(default)]
+    desaturate: f32,
+    #[serde(default)]
+    saturate: f32,
+    #[serde(default)]
+    hue_shift: f32,
+    #[serde(default)]
+    color: Option<ColorFunction>,
+}
+
+// A gradient between palette colors, also declared among a section's tones,
+// e.g. `"fade": {"linear": [0, 0, 0, 1], "stops": [[0, "hair_color"], [1,
+// "hair_color_tip"]]}` or `{"radial": [cx, cy, r], ...}`. Coordinates are
+// fractions of the box around the shapes painted with it. A stop names any
+// section color, tone or outline and can add an opacity as a third element.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum GradientSpec {
+    Linear { linear: (f64, f64, f64, f64), stops: Vec<RawGradientStop> },
+    Radial { radial: (f64, f64, f64), stops: Vec<RawGradientStop> },
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawGradientStop {
+    Opaque(f64, String),
+    Translucent(f64, String, f64),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawTone {
+    Gradient(GradientSpec),
+    Tone(ToneSpec),
+}
+
+// `[name, variants]` or `[name, variants, tones]`
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawSection {
+    Plain(String, PaletteSection),
+    WithTones(String, PaletteSection, BTreeMap<String, RawTone>),
+}
+type RawPalette = Vec<RawSection>;
+
+impl ToneSpec {
+    fn derive(&self, rgb: LinSrgb) -> LinSrgb {
+        let hsl = linear_to_srgb_hsl(rgb);
+        let lightness = hsl.lightness * (1.0 - self.darken);
+        let lightness = lightness + (1.0 - lightness) * self.lighten;
+        let saturation = hsl.saturation * (1.0 - self.desaturate);
+        let saturation = saturation + (1.0 - saturation) * self.saturate;
+        srgb_hsl_to_linear(Color::Hsl(Hsl::new(hsl.hue + self.hue_shift * 360.0, saturation, lightness)))
+    }
+
+    // A shift in degrees would wrap around silently, like a hue
+    fn check(&self) -> Result<(), String> {
+        if !(-1.0..=1.0).contains(&self.hue_shift) {
+            return Err(format!("hue shift {} isn't between -1 and 1", self.hue_shift));
+        }
+        self.color.as_ref().map_or(Ok(()), |color| color.check_hue())
+    }
+}
+
+impl RawGradientStop {
+    fn parts(&self) -> (f64, &str, f64) {
+        match self {
+            RawGradientStop::Opaque(offset, class) => (*offset, class, 1.0),
+            RawGradientStop::Translucent(offset, class, opacity) => (*offset, class, *opacity),
+        }
+    }
+}
+
+impl GradientSpec {
+    fn stops(&self) -> &[RawGradientStop] {
+        match self {
+            GradientSpec::Linear { stops, .. } | GradientSpec::Radial { stops, .. } => stops,
+        }
+    }
+
+    fn resolve(&self, colors: &HashMap<String, LinSrgb>) -> Gradient {
+        let shape = match *self {
+            GradientSpec::Linear { linear: (x1, y1, x2, y2), .. } => GradientShape::Linear { x1, y1, x2, y2 },
+            GradientSpec::Radial { radial: (cx, cy, r), .. } => GradientShape::Radial { cx, cy, r },
+        };
+        let stops = self.stops().iter().map(|stop| {
+            let (offset, class, opacity) = stop.parts();
+            let rgb = colors[class];
+            GradientStop { offset, color: rgba_to_svg(&LinSrgba::new(rgb.red, rgb.green, rgb.blue, opacity as f32)) }
+        }).collect();
+        Gradient { shape, stops }
+    }
+}
+
+// Where a gradient runs, in fractions of the box around the shapes it paints
+#[derive(Clone, Debug, PartialEq)]
+pub enum GradientShape {
+    Linear { x1: f64, y1: f64, x2: f64, y2: f64 },
+    Radial { cx: f64, cy: f64, r: f64 },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct GradientStop {
+    pub offset: f64,
+    // `#rrggbb`, or `#rrggbbaa` for a translucent stop
+    pub color: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Gradient {
+    pub shape: GradientShape,
+    pub stops: Vec<GradientStop>,
+}
+
+// What the shapes with a palette class are filled or stroked with
+#[derive(Clone, Debug, PartialEq)]
+pub enum Paint {
+    Color(String),
+    Gradient(Gradient),
+}
+
+// The outline of sections that don't declare one
+fn default_outline(rgb: LinSrgb) -> LinSrgb {
+    let hsl = linear_to_srgb_hsl(rgb);
+    srgb_hsl_to_linear(Color::Hsl(
+        hsl.darken(hsl.lightness - hsl.lightness * 0.6).
+        desaturate(hsl.saturation - hsl.saturation * 0.6)
+    ))
+}
+
+// One step of a theme. Lightness, saturation and hue steps work like the
+// amounts of a tone, so `hue_shift` is a fraction of a turn between -1 and
+// 1, `monochrome` moves towards the grey of the same luminance, `tint` mixes
+// in an HSL color as in palette.json, `contrast` pushes channels away from
+// the middle and `posterize` rounds them to a number of levels.
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(rename_all = "snake_case")]
+enum ThemeStep {
+    Darken(f32),
+    Lighten(f32),
+    Desaturate(f32),
+    Saturate(f32),
+    HueShift(f32),
+    Monochrome(f32),
+    Tint((f32, f32, f32), f32),
+    Contrast(f32),
+    Posterize(u8),
+}
+
+impl ThemeStep {
+    fn apply(&self, rgb: LinSrgb) -> LinSrgb {
+        match *self {
+            ThemeStep::Darken(darken) => ToneSpec { darken, ..ToneSpec::default() }.derive(rgb),
+            ThemeStep::Lighten(lighten) => ToneSpec { lighten, ..ToneSpec::default() }.derive(rgb),
+            ThemeStep::Desaturate(desaturate) => ToneSpec { desaturate, ..ToneSpec::default() }.derive(rgb),
+            ThemeStep::Saturate(saturate) => ToneSpec { saturate, ..ToneSpec::default() }.derive(rgb),
+            ThemeStep::HueShift(hue_shift) => ToneSpec { hue_shift, ..ToneSpec::default() }.derive(rgb),
+            ThemeStep::Monochrome(amount) => {
+                let luminance = 0.2126 * rgb.red + 0.7152 * rgb.green + 0.0722 * rgb.blue;
+                rgb.mix(&LinSrgb::new(luminance, luminance, luminance), amount)
+            }
+            ThemeStep::Tint((h, s, l), amount) => rgb.mix(&srgb_hsl_to_linear(Color::Hsl(Hsl::new(h * 360.0, s, l))), amount),
+            ThemeStep::Contrast(amount) => map_encoded(rgb, |c| 0.5 + (c - 0.5) * (1.0 + amount)),
+            ThemeStep::Posterize(levels) => {
+                let steps = f32::from(levels.max(2) - 1);
+                map_encoded(rgb, |c| (c * steps).round() / steps)
+            }
+        }
+    }
+
+    fn check(&self) -> Result<(), String> {
+        match *self {
+            ThemeStep::HueShift(hue_shift) => ToneSpec { hue_shift, ..ToneSpec::default() }.check(),
+            ThemeStep::Tint((h, _, _), _) if !(0.0..=1.0).contains(&h) => Err(format!("tint hue {} isn't between 0 and 1", h)),
+            _ => Ok(()),
+        }
+    }
+}
+
+// Applies `f` to the gamma encoded channels of a color, clamping them to the
+// sRGB gamut on both sides
+fn map_encoded<F: Fn(f32) -> f32>(rgb: LinSrgb, f: F) -> LinSrgb {
+    let clamp = |c: f32| c.max(0.0).min(1.0);
+    let encoded = Srgb::from_linear(LinSrgb::new(clamp(rgb.red), clamp(rgb.green), clamp(rgb.blue)));
+    Srgb::new(clamp(f(encoded.red)), clamp(f(encoded.green)), clamp(f(encoded.blue))).into_linear()
+}
+
+// A restyling of every palette color, applied after sampling so the recipe
+// keeps the original colors. Themes come from themes.json, e.g.
+// `"sepia": [{"monochrome": 1.0}, {"tint": [[0.09, 0.55, 0.42], 0.35]}]`.
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Theme(Vec<ThemeStep>);
+
+impl Theme {
+    pub fn apply(&self, rgb: LinSrgb) -> LinSrgb {
+        self.0.iter().fold(rgb, |rgb, step| step.apply(rgb))
+    }
+}
+
+pub fn themes_from_file(path: &Path) -> serde_json::Result<BTreeMap<String, Theme>> {
+    let file = File::open(path).map_err(serde_json::Error::io)?;
+    themes_from_reader(BufReader::new(file))
+}
+
+fn themes_from_reader<R: std::io::Read>(reader: R) -> serde_json::Result<BTreeMap<String, Theme>> {
+    let themes: BTreeMap<String, Theme> = serde_json::from_reader(reader)?;
+    for (name, theme) in &themes {
+        for step in &theme.0 {
+            step.check().map_err(|e| serde::de::Error::custom(format!("theme {}: {}", name, e)))?;
+        }
+    }
+    Ok(themes)
+}
+
+fn sample_component<R: Rng>(p: &ColorComponent, rng: &mut R) -> f32 {
     match p {
         ColorComponent::Range(start, end) => {
-            let mut rng = rand::thread_rng();
             rng.gen_range(start, end)
         }
         ColorComponent::Constant(value) => *value,
     }
 }
 
-fn rec_choose_variant(palette: &HashMap<String, HashMap<String, HashMap<String, HashMap<String, RawPaletteVarient>>>>, palette_type: &str, palette_subtype: &str, section: &String, constraints: Option<&Vec<&String>>, values_chosen: &mut HashMap<String, (String, Color)>) {
-    if !values_chosen.contains_key(section) {
-        let mut rng = rand::thread_rng();
-        let sub_section = &palette[section];
-        let sub_section = if sub_section.contains_key(palette_type) {
-            &sub_section[palette_type]
-        } else {
-            &sub_section["default"]
-        };
-        let sub_section = if sub_section.contains_key(palette_subtype) {
-            &sub_section[palette_subtype]
-        } else {
-            &sub_section["default"]
-        };
+// Björn Ottosson's Oklab to linear sRGB transform
+fn oklab_to_linear(l: f32, a: f32, b: f32) -> LinSrgb {
+    let l_ = (l + 0.396_337_78 * a + 0.215_803_76 * b).powi(3);
+    let m_ = (l - 0.105_561_346 * a - 0.063_854_17 * b).powi(3);
+    let s_ = (l - 0.089_484_18 * a - 1.291_485_5 * b).powi(3);
+    LinSrgb::new(
+        4.076_741_7 * l_ - 3.307_711_6 * m_ + 0.230_969_94 * s_,
+        -1.268_438 * l_ + 2.609_757_4 * m_ - 0.341_319_38 * s_,
+        -0.004_196_086_3 * l_ - 0.703_418_6 * m_ + 1.707_614_7 * s_,
+    )
+}
 
-        let options:Vec<&String> = match constraints {
-            Some(constraints) => constraints.iter().cloned().filter(|k| sub_section.contains_key(&k.to_string())).collect(),
-            None => sub_section.keys().collect(),
-        };
-        assert!(options.len() > 0);
-        let variant = options.iter().choose(&mut rng).unwrap();
-        let config = &sub_section[*variant];
-        let color_function = match config {
-            RawPaletteVarient::Color(func) => func,
-            RawPaletteVarient::ColorWithSubchoice(func, sub_choices) => {
-                for (section, constraints) in sub_choices.iter() {
-                    rec_choose_variant(palette, palette_type, palette_subtype, section, Some(&constraints.iter().collect()), values_chosen);
+// palette's Hsl is HSL over linear RGB, but palette.json means the usual
+// HSL over sRGB encoded values. These carry the encoded components through a
+// LinSrgb without converting them.
+fn srgb_hsl_to_linear(color: Color) -> LinSrgb {
+    let encoded = LinSrgb::from(color);
+    Srgb::new(encoded.red, encoded.green, encoded.blue).into_linear()
+}
+
+fn linear_to_srgb_hsl(rgb: LinSrgb) -> Hsl {
+    let encoded = Srgb::from_linear(rgb);
+    Hsl::from(LinSrgb::new(encoded.red, encoded.green, encoded.blue))
+}
+
+fn sample_color<R: Rng>(color_function: &ColorFunction, rng: &mut R) -> LinSrgb {
+    match color_function {
+        ColorFunction::HSL(h, s, l) => {
+            let h = sample_component(&h, rng);
+            let s = sample_component(&s, rng);
+            let l = sample_component(&l, rng);
+            srgb_hsl_to_linear(Color::Hsl(Hsl::new(h * 360.0, s, l)))
+        },
+        ColorFunction::Lch { lch: (l, c, h) } => {
+            let l = sample_component(&l, rng);
+            let c = sample_component(&c, rng);
+            let h = sample_component(&h, rng);
+            LinSrgb::from(Lch::new(l, c, h * 360.0))
+        },
+        ColorFunction::Oklab { oklab: (l, a, b) } => {
+            let l = sample_component(&l, rng);
+            let a = sample_component(&a, rng);
+            let b = sample_component(&b, rng);
+            oklab_to_linear(l, a, b)
+        },
+        ColorFunction::SkinModel((alpha_start, alpha_end), (ph, ps, pl), (bh, bs, bl)) =>
+        {
+            let a1 = rng.gen_range(alpha_start, alpha_end);
+            // FIXME: If I use Hsla directly it ignores alpha when I composite
+            // probably a bug in palette?
+            let p1 = LinSrgba::from(Hsla::new(*ph, *ps/100.0, *pl/100.0, a1));
+            let b = LinSrgba::from(Hsla::new(*bh, *bs/100.0, *bl/100.0, 1.0));
+
+            srgb_hsl_to_linear(Color::from(p1.over(b)))
+        },
+    }
+}
+
+fn section_variants<'a>(palette: &'a HashMap<String, PaletteSection>, section: &str, palette_type: &str, palette_subtype: &str) -> &'a HashMap<String, RawPaletteVarient> {
+    let sub_section = &palette[section];
+    let sub_section = if sub_section.contains_key(palette_type) {
+        &sub_section[palette_type]
+    } else {
+        &sub_section["default"]
+    };
+    if sub_section.contains_key(palette_subtype) {
+        &sub_section[palette_subtype]
+    } else {
+        &sub_section["default"]
+    }
+}
+
+// Weight rules see palette variants at `<attributes>:palette:<section>:<variant>`,
+// e.g. `:species:elf:age:adult:sex:female:palette:hair_color:red`
+pub fn palette_weight_path(attributes_path: &str, section: &str, variant: &str) -> String {
+    format!("{}:palette:{}:{}", attributes_path, section, variant)
+}
+
+// The variants among `options` that can be drawn, with their weights. A
+// variant the rules make "always" is the only one, otherwise the rule weight
+// scales the weight from palette.json and variants weighing 0 are dropped.
+// Each lookup goes into `trace` when there is one.
+fn weighted_options<'a>(sub_section: &HashMap<String, RawPaletteVarient>, section: &str, options: &[&'a String], weights: &Weights, attributes_path: &str, trace: Option<&RefCell<Vec<Lookup>>>) -> Vec<(&'a String, f32)> {
+    let mut weighted = Vec::with_capacity(options.len());
+    for variant in options {
+        let path = palette_weight_path(attributes_path, section, variant);
+        let weight = weights.for_path(&path);
+        if let Some(trace) = trace {
+            trace.borrow_mut().push(Lookup::new(weights, &path, weight));
+        }
+        match weight {
+            Weight::Always => return vec![(variant, 1.0)],
+            Weight::Sometimes(weight) => weighted.push((*variant, weight * sub_section[*variant].weight())),
+        }
+    }
+    weighted.retain(|(_, weight)| *weight > 0.0);
+    weighted
+}
+
+// Linear channels are clamped to the sRGB gamut, gamma encoded and rounded
+// to a byte
+fn encode_channel(linear: f32) -> u8 {
+    let encoded = Srgb::from_linear(LinSrgb::new(linear.max(0.0).min(1.0), 0.0, 0.0)).red;
+    (encoded * 255.0).round() as u8
+}
+
+// `#rrggbb` for a linear color
+pub fn rgb_to_svg(rgb: &LinSrgb) -> String {
+    format!("#{:02x}{:02x}{:02x}", encode_channel(rgb.red), encode_channel(rgb.green), encode_channel(rgb.blue))
+}
+
+// `#rrggbbaa` for a linear color with alpha, or `#rrggbb` when it's opaque.
+// Alpha isn't gamma encoded.
+pub fn rgba_to_svg(rgba: &LinSrgba) -> String {
+    let rgb = rgb_to_svg(&rgba.color);
+    if rgba.alpha >= 1.0 {
+        return rgb;
+    }
+    format!("{}{:02x}", rgb, (rgba.alpha.max(0.0) * 255.0).round() as u8)
+}
+
+// The colors a face was drawn with, to match UI elements to a character
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ResolvedPalette {
+    // Section to variant name, e.g. `hair_color: red`
+    pub variants: BTreeMap<String, String>,
+    // Final `#rrggbb` colors keyed by class name, including tones and
+    // `_outline` colors. Gradients are left out, their stops are all here.
+    pub colors: BTreeMap<String, String>,
+}
+
+impl ResolvedPalette {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    // The colors as CSS custom properties under `selector`, e.g.
+    // `.nameplate { --hair_color: #a0522d; ... }`
+    pub fn to_css(&self, selector: &str) -> String {
+        let mut css = format!("{} {{\n", selector);
+        for (name, color) in &self.colors {
+            css.push_str(&format!("    --{}: {};\n", name, color));
+        }
+        css.push_str("}\n");
+        css
+    }
+}
+
+pub struct PaletteSpec {
+    sections: Vec<String>,
+    palette: HashMap<String, PaletteSection>,
+    tones: HashMap<String, BTreeMap<String, ToneSpec>>,
+    gradients: HashMap<String, BTreeMap<String, GradientSpec>>,
+}
+
+impl PaletteSpec {
+    pub fn from_file(path: &Path) -> Self {
+        let file = File::open(path).unwrap();
+        let reader = BufReader::new(file);
+        Self::from_raw(serde_json::from_reader(reader).unwrap())
+    }
+
+    fn from_raw(raw_palette: RawPalette) -> Self {
+        let mut sections = Vec::new();
+        let mut palette = HashMap::new();
+        let mut tones = HashMap::new();
+        let mut gradients = HashMap::new();
+        for raw_section in raw_palette {
+            let (name, variants) = match raw_section {
+                RawSection::Plain(name, variants) => (name, variants),
+                RawSection::WithTones(name, variants, raw_tones) => {
+                    let mut section_tones = BTreeMap::new();
+                    let mut section_gradients = BTreeMap::new();
+                    for (tone, raw_tone) in raw_tones {
+                        match raw_tone {
+                            RawTone::Tone(spec) => {
+                                section_tones.insert(tone, spec);
+                            }
+                            RawTone::Gradient(spec) => {
+                                section_gradients.insert(tone, spec);
+                            }
+                        }
+                    }
+                    tones.insert(name.clone(), section_tones);
+                    gradients.insert(name.clone(), section_gradients);
+                    (name, variants)
+                }
+            };
+            sections.push(name.clone());
+            palette.insert(name, variants);
+        }
+        let spec = Self { sections, palette, tones, gradients };
+        spec.check_gradient_stops();
+        spec.check_hues();
+        spec
+    }
+
+    // Gradient stops can only name flat colors
+    fn check_gradient_stops(&self) {
+        let mut colors = HashSet::new();
+        for section in &self.sections {
+            colors.insert(section.to_string());
+            colors.insert(format!("{}_outline", section));
+            for tone in self.tones.get(section).into_iter().flat_map(|t| t.keys()) {
+                colors.insert(format!("{}_{}", section, tone));
+            }
+        }
+        for (section, gradients) in &self.gradients {
+            for (name, gradient) in gradients {
+                for stop in gradient.stops() {
+                    let (_, class, _) = stop.parts();
+                    if !colors.contains(class) {
+                        panic!("gradient {}_{} has a stop with unknown color {}", section, name, class);
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_hues(&self) {
+        for (section, types) in &self.palette {
+            for (variant, config) in types.values().flat_map(|t| t.values()).flat_map(|s| s.iter()) {
+                if let Err(e) = config.color_function().check_hue() {
+                    panic!("{} variant {}: {}", section, variant, e);
+                }
+            }
+        }
+        for (section, tones) in &self.tones {
+            for (tone, spec) in tones {
+                if let Err(e) = spec.check() {
+                    panic!("{} tone {}: {}", section, tone, e);
+                }
+            }
+        }
+    }
+
+    fn rec_choose_variant<R: Rng>(&self, palette_type: &str, palette_subtype: &str, section: &String, constraints: Option<&Vec<&String>>, locks: &Constraints, replay: &BTreeMap<String, PaletteChoice>, weights: &Weights, attributes_path: &str, trace: Option<&RefCell<Vec<Lookup>>>, values_chosen: &mut BTreeMap<String, PaletteChoice>, rng: &mut R) -> Result<(), GenerateError> {
+        if !values_chosen.contains_key(section) {
+            let sub_section = section_variants(&self.palette, section, palette_type, palette_subtype);
+
+            let mut options:Vec<&String> = match constraints {
+                Some(constraints) => constraints.iter().cloned().filter(|k| sub_section.contains_key(&k.to_string())).collect(),
+                None => sub_section.keys().collect(),
+            };
+            if let Some(locked) = locks.palette.get(section) {
+                options.retain(|v| locked.contains(*v));
+            }
+            options.sort();
+            let weighted = weighted_options(sub_section, section, &options, weights, attributes_path, trace);
+            if weighted.is_empty() {
+                let mut restrictions = Vec::new();
+                if let Some(constraints) = constraints {
+                    let allowed: Vec<&str> = constraints.iter().map(|c| c.as_str()).collect();
+                    restrictions.push(format!("{} in {{{}}}", section, allowed.join(",")));
+                }
+                if locks.palette.contains_key(section) {
+                    restrictions.push(locks.describe_palette(section));
+                }
+                let mut rules = Vec::new();
+                for variant in &options {
+                    for rule in weights.rules_for_path(&palette_weight_path(attributes_path, section, variant)) {
+                        let rule = rule.to_string();
+                        if !rules.contains(&rule) {
+                            rules.push(rule);
+                        }
+                    }
+                }
+                return Err(GenerateError::Conflict {
+                    constraint: if restrictions.is_empty() { None } else { Some(restrictions.join(" and ")) },
+                    path: format!("palette:{}:{}:{}", palette_type, palette_subtype, section),
+                    rules,
+                });
+            }
+            let replayed = replay.get(section).filter(|choice| weighted.iter().any(|(v, _)| **v == choice.variant));
+            let variant = match replayed {
+                Some(choice) => &choice.variant,
+                None => weighted.choose_weighted(rng, |(_, w)| *w).unwrap().0,
+            };
+            let config = &sub_section[variant];
+            if let Some(sub_choices) = config.sub_choices() {
+                let mut sub_choices: Vec<(&String, &Vec<String>)> = sub_choices.iter().collect();
+                sub_choices.sort();
+                for (section, constraints) in sub_choices {
+                    self.rec_choose_variant(palette_type, palette_subtype, section, Some(&constraints.iter().collect()), locks, replay, weights, attributes_path, trace, values_chosen, rng)?;
+                }
+            }
+            let mut choice = match replayed {
+                Some(choice) => choice.clone(),
+                None => {
+                    let rgb = sample_color(config.color_function(), rng);
+                    PaletteChoice { variant: variant.to_string(), color: [rgb.red, rgb.green, rgb.blue], tones: BTreeMap::new() }
                 }
-                func
-            },
+            };
+            // Tones with a color of their own are sampled after the section's color
+            for (tone, spec) in self.tones.get(section.as_str()).into_iter().flatten() {
+                if let Some(color_function) = &spec.color {
+                    if !choice.tones.contains_key(tone) {
+                        let rgb = sample_color(color_function, rng);
+                        choice.tones.insert(tone.to_string(), [rgb.red, rgb.green, rgb.blue]);
+                    }
+                }
+            }
+            values_chosen.insert(section.to_string(), choice);
+        }
+        Ok(())
+    }
+
+    // Every section's color along with its derived tones and gradients, keyed
+    // by class name. The theme, if any, restyles each color after its tones
+    // are derived.
+    pub fn palette_from_choices(&self, choices: &BTreeMap<String, PaletteChoice>, theme: Option<&Theme>) -> Palette {
+        let themed = |rgb: LinSrgb| theme.map_or(rgb, |theme| theme.apply(rgb));
+        let mut colors = HashMap::new();
+        for (section, choice) in choices.iter() {
+            let [red, green, blue] = choice.color;
+            let rgb = LinSrgb::new(red, green, blue);
+            colors.insert(section.to_string(), themed(rgb));
+            let tones = self.tones.get(section);
+            if !tones.map_or(false, |t| t.contains_key("outline")) {
+                colors.insert(format!("{}_outline", section), themed(default_outline(rgb)));
+            }
+            for (tone, spec) in tones.into_iter().flatten() {
+                let toned = match (&spec.color, choice.tones.get(tone)) {
+                    (Some(_), Some([red, green, blue])) => LinSrgb::new(*red, *green, *blue),
+                    _ => spec.derive(rgb),
+                };
+                colors.insert(format!("{}_{}", section, tone), themed(toned));
+            }
+        }
+        let mut palette: Palette = colors.iter().map(|(class, rgb)| (class.to_string(), Paint::Color(rgb_to_svg(rgb)))).collect();
+        for section in choices.keys() {
+            for (name, spec) in self.gradients.get(section).into_iter().flatten() {
+                palette.insert(format!("{}_{}", section, name), Paint::Gradient(spec.resolve(&colors)));
+            }
+        }
+        palette
+    }
+
+    pub fn resolve(&self, choices: &BTreeMap<String, PaletteChoice>, theme: Option<&Theme>) -> ResolvedPalette {
+        let variants = choices.iter().map(|(section, choice)| (section.to_string(), choice.variant.to_string())).collect();
+        let colors = self.palette_from_choices(choices, theme).into_iter().filter_map(|(class, paint)| match paint {
+            Paint::Color(color) => Some((class, color)),
+            Paint::Gradient(_) => None,
+        }).collect();
+        ResolvedPalette { variants, colors }
+    }
+
+    // Tones and gradients a section declares in palette.json, as class names
+    pub fn declared_names(&self, section: &str) -> Vec<String> {
+        let tones = self.tones.get(section).into_iter().flat_map(|t| t.keys());
+        let gradients = self.gradients.get(section).into_iter().flat_map(|g| g.keys());
+        tones.chain(gradients).map(|name| format!("{}_{}", section, name)).collect()
+    }
+
+    // Every class the palette colors: sections, their outlines and the tones
+    // and gradients they declare
+    pub fn class_names(&self) -> BTreeSet<String> {
+        let mut names = BTreeSet::new();
+        for section in &self.sections {
+            names.insert(section.to_string());
+            names.insert(format!("{}_outline", section));
+            names.extend(self.declared_names(section));
+        }
+        names
+    }
+
+    // Classes used as gradient stops
+    pub fn gradient_stop_names(&self) -> BTreeSet<&str> {
+        self.gradients.values().flat_map(|g| g.values()).flat_map(|g| g.stops()).map(|stop| stop.parts().1).collect()
+    }
+
+    pub fn sections(&self) -> impl Iterator<Item = &str> {
+        self.sections.iter().map(|s| s.as_str())
+    }
+
+    pub fn variants(&self, section: &str) -> Option<BTreeSet<&String>> {
+        let sub_sections = self.palette.get(section)?;
+        Some(sub_sections.values().flat_map(|s| s.values()).flat_map(|s| s.keys()).collect())
+    }
+
+    // Sections already present in `replay` keep their recorded variant and
+    // color as long as the variant is still allowed, everything else is sampled.
+    // `attributes_path` is where the weight paths for variants start, and their
+    // lookups are recorded in `trace` if given. Turn the choices into colors
+    // with `palette_from_choices`.
+    pub fn sample<R: Rng>(&self, palette_type: &str, palette_subtype: &str, locks: &Constraints, replay: &BTreeMap<String, PaletteChoice>, weights: &Weights, attributes_path: &str, trace: Option<&RefCell<Vec<Lookup>>>, rng: &mut R) -> Result<(String, BTreeMap<String, PaletteChoice>), GenerateError> {
+        let mut values_chosen = BTreeMap::new();
+        for section in &self.sections {
+            self.rec_choose_variant(palette_type, palette_subtype, section, None, locks, replay, weights, attributes_path, trace, &mut values_chosen, rng)?;
+        }
+
+        let variants = values_chosen.iter().map(|(k, v)| (k.clone(), v.variant.clone())).collect();
+        let palette_path = palette_path(palette_type, &variants);
+        Ok((palette_path, values_chosen))
+    }
+
+    // Every combination of variants `sample` can pick for a palette type and
+    // subtype along with its probability, and the probability that sampling
+    // finds no variant for some section.
+    pub fn variant_combinations(&self, palette_type: &str, palette_subtype: &str, weights: &Weights, attributes_path: &str) -> (Vec<(BTreeMap<String, String>, f64)>, f64) {
+        let pending: Vec<(&String, Option<&Vec<String>>)> = self.sections.iter().map(|s| (s, None)).collect();
+        let mut combinations = Vec::new();
+        let failure = self.rec_variant_combinations(palette_type, palette_subtype, weights, attributes_path, &pending, &BTreeMap::new(), 1.0, &mut combinations);
+        (combinations, failure)
+    }
+
+    // Every weight path `sample` can look up for a palette type and subtype
+    pub fn weight_paths(&self, palette_type: &str, palette_subtype: &str, attributes_path: &str) -> Vec<String> {
+        let mut paths = Vec::new();
+        for section in &self.sections {
+            for variant in section_variants(&self.palette, section, palette_type, palette_subtype).keys() {
+                paths.push(palette_weight_path(attributes_path, section, variant));
+            }
+        }
+        paths
+    }
+
+    fn rec_variant_combinations(&self, palette_type: &str, palette_subtype: &str, weights: &Weights, attributes_path: &str, pending: &[(&String, Option<&Vec<String>>)], chosen: &BTreeMap<String, String>, probability: f64, combinations: &mut Vec<(BTreeMap<String, String>, f64)>) -> f64 {
+        let (section, constraints) = match pending.first() {
+            Some(next) => *next,
+            None => {
+                combinations.push((chosen.clone(), probability));
+                return 0.0;
+            }
         };
-        let color = match color_function {
-            ColorFunction::HSL(h, s, l) => {
-                let h = sample_component(&h);
-                let s = sample_component(&s);
-                let l = sample_component(&l);
-                Color::Hsl(Hsl::new(h * 360.0, s, l))
-            },
-            ColorFunction::SkinModel((alpha_start, alpha_end), (ph, ps, pl), (bh, bs, bl)) =>
-            {
-                let mut rng = rand::thread_rng();
-                let a1 = rng.gen_range(alpha_start, alpha_end);
-                // FIXME: If I use Hsla directly it ignores alpha when I composite
-                // probably a bug in palette?
-                let p1 = LinSrgba::from(Hsla::new(*ph, *ps/100.0, *pl/100.0, a1));
-                let b = LinSrgba::from(Hsla::new(*bh, *bs/100.0, *bl/100.0, 1.0));
-
-                Color::from(p1.over(b))
-            },
+        if chosen.contains_key(section) {
+            return self.rec_variant_combinations(palette_type, palette_subtype, weights, attributes_path, &pending[1..], chosen, probability, combinations);
+        }
+
+        let sub_section = section_variants(&self.palette, section, palette_type, palette_subtype);
+        let mut options: Vec<&String> = match constraints {
+            Some(constraints) => constraints.iter().filter(|k| sub_section.contains_key(*k)).collect(),
+            None => sub_section.keys().collect(),
         };
-        values_chosen.insert(section.to_string(), (variant.to_string(), color));
+        options.sort();
+        let weighted = weighted_options(sub_section, section, &options, weights, attributes_path, None);
+        if weighted.is_empty() {
+            return probability;
+        }
+
+        let total: f64 = weighted.iter().map(|(_, w)| f64::from(*w)).sum();
+        let mut failure = 0.0;
+        for (variant, weight) in weighted {
+            let probability = probability * f64::from(weight) / total;
+            let mut next = Vec::new();
+            if let Some(sub_choices) = sub_section[variant].sub_choices() {
+                let mut sub_choices: Vec<(&String, &Vec<String>)> = sub_choices.iter().collect();
+                sub_choices.sort();
+                next.extend(sub_choices.into_iter().map(|(s, c)| (s, Some(c))));
+            }
+            next.extend_from_slice(&pending[1..]);
+            let mut chosen = chosen.clone();
+            chosen.insert(section.to_string(), variant.to_string());
+            failure += self.rec_variant_combinations(palette_type, palette_subtype, weights, attributes_path, &next, &chosen, probability, combinations);
+        }
+        failure
     }
 }
 
-fn rgb_to_svg(rgb: &LinSrgb) -> String {
-    let mut rgb_int = (rgb.red * 256.0) as u32;
-    rgb_int = (rgb_int << 8) + (rgb.green * 256.0) as u32;
-    rgb_int = (rgb_int << 8) + (rgb.blue * 256.0) as u32;
-    format!("#{:01$x}", rgb_int, 6)
+// The palette part of a weight path, e.g. `human:hair_color:red:skin_color:light`
+pub fn palette_path(palette_type: &str, variants: &BTreeMap<String, String>) -> String {
+    let variants: Vec<String> = variants.iter().map(|(k, v)| format!("{}:{}", k, v)).collect();
+    format!("{}:{}", palette_type, variants.join(":"))
 }
 
-pub fn palette_from_file(path: &Path, palette_type: &str, palette_subtype: &str) -> (String, Palette) {
-    let mut palette = HashMap::new();
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channels_are_encoded_clamped_and_rounded() {
+        assert_eq!(rgb_to_svg(&LinSrgb::new(1.0, 0.0, 0.5)), "#ff00bc");
+        assert_eq!(rgb_to_svg(&LinSrgb::new(1.2, -0.1, 0.0)), "#ff0000");
+    }
+
+    #[test]
+    fn alpha_is_written_only_when_translucent() {
+        assert_eq!(rgba_to_svg(&LinSrgba::new(1.0, 0.0, 0.5, 1.0)), "#ff00bc");
+        assert_eq!(rgba_to_svg(&LinSrgba::new(1.0, 0.0, 0.5, 0.5)), "#ff00bc80");
+        assert_eq!(rgba_to_svg(&LinSrgba::new(0.0, 0.0, 0.0, 0.0)), "#00000000");
+        assert_eq!(rgba_to_svg(&LinSrgba::new(0.0, 1.0, 0.0, -0.5)), "#00ff0000");
+    }
+
+    #[test]
+    fn hsl_entries_keep_their_colors() {
+        // HSL 216°, 50%, 40% is (0.2, 0.36, 0.6) in sRGB, which scaled by 256
+        // and truncated was #335c99 before colors were stored linear
+        let hsl: ColorFunction = serde_json::from_str("[0.6, 0.5, 0.4]").unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        assert_eq!(rgb_to_svg(&sample_color(&hsl, &mut rng)), "#335c99");
+    }
+
+    #[test]
+    fn lch_hues_are_fractions_of_a_turn() {
+        let lch = ColorFunction::Lch { lch: (ColorComponent::Constant(60.0), ColorComponent::Constant(40.0), ColorComponent::Constant(0.25)) };
+        let mut rng = StdRng::seed_from_u64(0);
+        let sampled = sample_color(&lch, &mut rng);
+        let expected = LinSrgb::from(Lch::new(60.0, 40.0, 90.0));
+        assert!((sampled.red - expected.red).abs() < 1e-6 && (sampled.green - expected.green).abs() < 1e-6 && (sampled.blue - expected.blue).abs() < 1e-6);
+    }
+
+    #[test]
+    fn hues_in_degrees_are_rejected() {
+        let lch: ColorFunction = serde_json::from_str(r#"{"lch": [60, 40, 90]}"#).unwrap();
+        assert_eq!(lch.check_hue(), Err("hue 90..90 isn't between 0 and 1".to_string()));
+        let hsl: ColorFunction = serde_json::from_str("[[0.5, 40], 0.5, 0.5]").unwrap();
+        assert!(hsl.check_hue().is_err());
+        let hsl: ColorFunction = serde_json::from_str("[[0.05, 0.1], 0.5, 0.5]").unwrap();
+        assert_eq!(hsl.check_hue(), Ok(()));
+        let skin: ColorFunction = serde_json::from_str("[[0.5, 0.9], [18, 89, 11], [13, 97, 86]]").unwrap();
+        assert_eq!(skin.check_hue(), Ok(()));
+    }
+
+    fn spec(json: &str) -> PaletteSpec {
+        PaletteSpec::from_raw(serde_json::from_str(json).unwrap())
+    }
+
+    fn hsl(h: f32, s: f32, l: f32) -> LinSrgb {
+        srgb_hsl_to_linear(Color::Hsl(Hsl::new(h * 360.0, s, l)))
+    }
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-3
+    }
+
+    #[test]
+    fn tones_move_lightness_saturation_and_hue() {
+        let rgb = hsl(1.0 / 3.0, 0.5, 0.6);
+        let derived = |tone: ToneSpec| linear_to_srgb_hsl(tone.derive(rgb));
+        assert!(close(derived(ToneSpec { darken: 0.5, ..ToneSpec::default() }).lightness, 0.3));
+        assert!(close(derived(ToneSpec { lighten: 0.5, ..ToneSpec::default() }).lightness, 0.8));
+        assert!(close(derived(ToneSpec { desaturate: 0.5, ..ToneSpec::default() }).saturation, 0.25));
+        assert!(close(derived(ToneSpec { saturate: 0.5, ..ToneSpec::default() }).saturation, 0.75));
+        let shifted = derived(ToneSpec { hue_shift: 0.25, ..ToneSpec::default() });
+        assert!(close(shifted.hue.to_positive_degrees(), 210.0));
+        assert!(close(shifted.lightness, 0.6) && close(shifted.saturation, 0.5));
+    }
 
-    let file = File::open(path).unwrap();
-    let reader = BufReader::new(file);
-    let raw_palette: RawPalette = serde_json::from_reader(reader).unwrap();
-    let sections:Vec<String> = raw_palette.iter().map(|(k,v)| k.to_string()).collect();
-    let raw_palette:HashMap<String, HashMap<String, HashMap<String, HashMap<String, RawPaletteVarient>>>> = HashMap::from_iter(raw_palette);
+    #[test]
+    fn hue_shifts_are_fractions_of_a_turn() {
+        let rgb = hsl(0.1, 0.5, 0.6);
+        assert_eq!(rgb_to_svg(&ToneSpec { hue_shift: 1.0, ..ToneSpec::default() }.derive(rgb)), rgb_to_svg(&rgb));
+        assert_eq!(rgb_to_svg(&ToneSpec { hue_shift: -0.5, ..ToneSpec::default() }.derive(rgb)), rgb_to_svg(&hsl(0.6, 0.5, 0.6)));
+        let degrees: ToneSpec = serde_json::from_str(r#"{"darken": 0.3, "hue_shift": -10}"#).unwrap();
+        assert_eq!(degrees.check(), Err("hue shift -10 isn't between -1 and 1".to_string()));
+        let color: ToneSpec = serde_json::from_str(r#"{"color": [[0.1, 1.5], 0.5, 0.5]}"#).unwrap();
+        assert!(color.check().is_err());
+    }
+
+    #[test]
+    #[should_panic(expected = "skin_color tone shadow: hue shift 20 isn't between -1 and 1")]
+    fn hue_shifts_in_degrees_are_rejected() {
+        spec(r#"[["skin_color", {"default": {"default": {"tan": [0.1, 0.5, 0.5]}}}, {"shadow": {"hue_shift": 20}}]]"#);
+    }
+
+    fn resolved() -> ResolvedPalette {
+        let mut palette = ResolvedPalette::default();
+        palette.variants.insert("hair_color".to_string(), "red".to_string());
+        palette.colors.insert("hair_color".to_string(), "#a0522d".to_string());
+        palette.colors.insert("hair_color_outline".to_string(), "#40210f".to_string());
+        palette
+    }
+
+    #[test]
+    fn resolved_palettes_export_as_json() {
+        let json = resolved().to_json();
+        assert_eq!(json, r##"{"variants":{"hair_color":"red"},"colors":{"hair_color":"#a0522d","hair_color_outline":"#40210f"}}"##);
+        assert_eq!(serde_json::from_str::<ResolvedPalette>(&json).unwrap(), resolved());
+    }
 
-    let mut values_chosen = HashMap::new();
-    for section in sections {
-        rec_choose_variant(&raw_palette, palette_type, palette_subtype, &section, None, &mut values_chosen);
+    #[test]
+    fn resolved_palettes_export_as_css_properties() {
+        assert_eq!(resolved().to_css(".nameplate"), ".nameplate {\n    --hair_color: #a0522d;\n    --hair_color_outline: #40210f;\n}\n");
+        assert_eq!(ResolvedPalette::default().to_css(":root"), ":root {\n}\n");
     }
 
-    for (section, (variant, color)) in values_chosen.iter() {
-        let hsl = Hsl::from(*color);
-        let rgb = LinSrgb::from(hsl);
-        palette.insert(section.to_string(), rgb_to_svg(&rgb));
-        let rgb = LinSrgb::from(
-            hsl.darken(hsl.lightness - hsl.lightness * 0.6).
-            desaturate(hsl.saturation - hsl.saturation * 0.6)
-        );
-        palette.insert(
-            format!("{}_outline", section),
-            rgb_to_svg(&rgb),
-        );
+    #[test]
+    fn resolving_keeps_variants_and_themes_colors() {
+        let spec = spec(r#"[["hair_color", {"default": {"default": {"red": [0.0, 1.0, 0.5]}}}, {"outline": {"darken": 0.5}}]]"#);
+        let weights = Weights::from_legacy("").unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let (_, choices) = spec.sample("default", "default", &Constraints::default(), &BTreeMap::new(), &weights, "", None, &mut rng).unwrap();
+        let red = hsl(0.0, 1.0, 0.5);
+        let plain = spec.resolve(&choices, None);
+        assert_eq!(plain.variants["hair_color"], "red");
+        assert_eq!(plain.colors["hair_color"], rgb_to_svg(&red));
+        assert_eq!(plain.colors["hair_color_outline"], rgb_to_svg(&ToneSpec { darken: 0.5, ..ToneSpec::default() }.derive(red)));
+        let theme = Theme(vec![step(r#"{"monochrome": 1.0}"#)]);
+        let themed = spec.resolve(&choices, Some(&theme));
+        assert_eq!(themed.variants, plain.variants);
+        assert_eq!(themed.colors["hair_color"], rgb_to_svg(&theme.apply(red)));
+    }
+
+    fn step(json: &str) -> ThemeStep {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn theme_steps_restyle_colors() {
+        let rgb = hsl(1.0 / 3.0, 0.5, 0.6);
+        let applied = |json: &str| linear_to_srgb_hsl(step(json).apply(rgb));
+        assert!(close(applied(r#"{"desaturate": 0.5}"#).saturation, 0.25));
+        let shifted = applied(r#"{"hue_shift": 0.25}"#);
+        assert!(close(shifted.hue.to_positive_degrees(), 210.0));
+        assert!(close(shifted.lightness, 0.6) && close(shifted.saturation, 0.5));
+        assert_eq!(rgb_to_svg(&step(r#"{"tint": [[0.6, 0.5, 0.4], 1.0]}"#).apply(rgb)), rgb_to_svg(&hsl(0.6, 0.5, 0.4)));
+        assert_eq!(rgb_to_svg(&step(r#"{"tint": [[0.6, 0.5, 0.4], 0.0]}"#).apply(rgb)), rgb_to_svg(&rgb));
+    }
+
+    #[test]
+    fn contrast_and_posterize_work_on_encoded_channels() {
+        let grey = |c: f32| Srgb::new(c, c, c).into_linear();
+        let encoded = |rgb: LinSrgb| Srgb::from_linear(rgb).red;
+        assert!(close(encoded(step(r#"{"contrast": 1.0}"#).apply(grey(0.6))), 0.7));
+        assert!(close(encoded(step(r#"{"contrast": 1.0}"#).apply(grey(0.9))), 1.0));
+        assert!(close(encoded(step(r#"{"contrast": 0.0}"#).apply(grey(0.3))), 0.3));
+        assert!(close(encoded(step(r#"{"posterize": 3}"#).apply(grey(0.3))), 0.5));
+        assert!(close(encoded(step(r#"{"posterize": 3}"#).apply(grey(0.2))), 0.0));
+        assert!(close(encoded(step(r#"{"posterize": 1}"#).apply(grey(0.7))), 1.0));
+    }
+
+    #[test]
+    fn theme_hue_shifts_are_fractions_of_a_turn() {
+        let themes = themes_from_reader(r#"{"warm": [{"hue_shift": -0.05}]}"#.as_bytes()).unwrap();
+        assert!(themes.contains_key("warm"));
+        let degrees = themes_from_reader(r#"{"warm": [{"hue_shift": -20}]}"#.as_bytes()).err().unwrap();
+        assert!(degrees.to_string().starts_with("theme warm: hue shift -20 isn't between -1 and 1"));
+        let tint = themes_from_reader(r#"{"sepia": [{"tint": [[30, 0.55, 0.42], 0.35]}]}"#.as_bytes()).err().unwrap();
+        assert!(tint.to_string().starts_with("theme sepia: tint hue 30 isn't between 0 and 1"));
+    }
+
+    #[test]
+    fn declared_tones_replace_derived_ones() {
+        let spec = spec(r#"[
+            ["skin_color", {"default": {"default": {"tan": [0.1, 0.5, 0.5]}}},
+             {"outline": {"darken": 0.5}, "shadow": {"darken": 0.3, "hue_shift": -0.03}, "blush": {"color": [0.0, 0.8, 0.6]}}],
+            ["hair_color", {"default": {"default": {"red": [0.0, 1.0, 0.5]}}}]
+        ]"#);
+        let weights = Weights::from_legacy("").unwrap();
+        let mut rng = StdRng::seed_from_u64(0);
+        let (_, choices) = spec.sample("default", "default", &Constraints::default(), &BTreeMap::new(), &weights, "", None, &mut rng).unwrap();
+        let palette = spec.palette_from_choices(&choices, None);
+        let color = |class: &str| match &palette[class] {
+            Paint::Color(color) => color.clone(),
+            Paint::Gradient(_) => panic!("{} is a gradient", class),
+        };
+        let skin = hsl(0.1, 0.5, 0.5);
+        assert_eq!(color("skin_color"), rgb_to_svg(&skin));
+        assert_eq!(color("skin_color_outline"), rgb_to_svg(&ToneSpec { darken: 0.5, ..ToneSpec::default() }.derive(skin)));
+        assert_ne!(color("skin_color_outline"), rgb_to_svg(&default_outline(skin)));
+        assert_eq!(color("skin_color_shadow"), rgb_to_svg(&ToneSpec { darken: 0.3, hue_shift: -0.03, ..ToneSpec::default() }.derive(skin)));
+        assert_eq!(color("hair_color_outline"), rgb_to_svg(&default_outline(hsl(0.0, 1.0, 0.5))));
+
+        // A tone with its own color is sampled and recorded, not derived
+        assert_eq!(color("skin_color_blush"), rgb_to_svg(&hsl(0.0, 0.8, 0.6)));
+        let mut replayed = choices.clone();
+        replayed.get_mut("skin_color").unwrap().tones.insert("blush".to_string(), [0.0, 0.0, 1.0]);
+        match &spec.palette_from_choices(&replayed, None)["skin_color_blush"] {
+            Paint::Color(blush) => assert_eq!(blush, "#0000ff"),
+            Paint::Gradient(_) => panic!("blush is a gradient"),
+        }
     }
-    let palette_path:Vec<String> = values_chosen.iter().map(|(k,v)| format!("{}:{}", k, v.0)).collect();
-    let palette_path = format!("{}:{}", palette_type, palette_path.join(":"));
-    (palette_path, palette)
 }
@@ -0,0 +1,330 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use super::recipe::FaceRecipe;
+use super::weights::RuleError;
+
+// Outcomes the generator is forced to pick from. Anything without an entry
+// is drawn normally. A template set containing `None` allows leaving the
+// guide empty.
+#[derive(Clone, Debug, Default)]
+pub struct Constraints {
+    pub templates: HashMap<String, BTreeSet<Option<String>>>,
+    pub options: HashMap<String, bool>,
+    pub palette: HashMap<String, BTreeSet<String>>,
+}
+
+impl Constraints {
+    pub fn describe_template(&self, name: &str) -> String {
+        let variations: Vec<&str> = self.templates[name]
+            .iter()
+            .map(|v| v.as_ref().map(|v| v.as_str()).unwrap_or("none"))
+            .collect();
+        format!("{}:{}", name, variations.join("|"))
+    }
+
+    pub fn describe_option(&self, name: &str) -> String {
+        format!("option_{}:{}", name, self.options[name])
+    }
+
+    pub fn describe_palette(&self, section: &str) -> String {
+        let variants: Vec<&str> = self.palette[section].iter().map(|v| v.as_str()).collect();
+        format!("{}:{}", section, variants.join("|"))
+    }
+
+    // The constraints as query clauses, so they can travel in a face code
+    pub fn to_query(&self) -> String {
+        let mut clauses = Vec::new();
+        for (name, variants) in &self.templates {
+            let variants: Vec<&str> = variants.iter().map(|v| v.as_ref().map_or("none", |v| v.as_str())).collect();
+            clauses.push(format!("{} in {{{}}}", name, variants.join(",")));
+        }
+        for (name, used) in &self.options {
+            clauses.push(format!("option_{}={}", name, used));
+        }
+        for (section, variants) in &self.palette {
+            let variants: Vec<&str> = variants.iter().map(|v| v.as_str()).collect();
+            clauses.push(format!("{} in {{{}}}", section, variants.join(",")));
+        }
+        clauses.sort();
+        clauses.join("; ")
+    }
+}
+
+// How the two sides of a constraint rule relate
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Relation {
+    // The right side has to hold whenever the left side does. The right side
+    // keeps its own odds, faces breaking the rule are drawn again.
+    Requires,
+    // The two sides never hold together. Whichever is decided second avoids
+    // the other.
+    Excludes,
+    // The right side is forced whenever the left side holds
+    Implies,
+}
+
+// One side of a constraint rule, written like a lock: `hat:conical`,
+// `hair:none`, `glasses` for any glasses at all, `nose:1|2`, `option_nosering`
+// or `option_nosering:false`
+#[derive(Clone, Debug, PartialEq)]
+pub enum Slot {
+    Template { name: String, variants: Vec<String> },
+    Option { name: String, used: bool },
+}
+
+// A decision being considered, a variant (`None` for an empty guide) for a
+// template category or an option being used or not
+#[derive(Copy, Clone, Debug)]
+pub enum Candidate<'a> {
+    Template(&'a str, Option<&'a str>),
+    Option(&'a str, bool),
+}
+
+#[derive(Clone, Debug)]
+pub struct ConstraintRule {
+    pub left: Slot,
+    pub relation: Relation,
+    pub right: Slot,
+    pub line: usize,
+}
+
+// Rules between features from the `constraints` file in the asset directory,
+// one `<slot> requires|excludes|implies <slot>` per line relating template
+// categories and options, e.g.
+//   # Conical hats sit on bare heads
+//   hat:conical implies hair:none
+//   nosering requires nose:1
+#[derive(Clone, Debug, Default)]
+pub struct ConstraintRules {
+    rules: Vec<ConstraintRule>,
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Slot::Template { name, variants } if variants.is_empty() => write!(f, "{}", name),
+            Slot::Template { name, variants } => write!(f, "{}:{}", name, variants.join("|")),
+            Slot::Option { name, used: true } => write!(f, "option_{}", name),
+            Slot::Option { name, used: false } => write!(f, "option_{}:false", name),
+        }
+    }
+}
+
+impl fmt::Display for ConstraintRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let relation = match self.relation {
+            Relation::Requires => "requires",
+            Relation::Excludes => "excludes",
+            Relation::Implies => "implies",
+        };
+        write!(f, "{} {} {} (line {})", self.left, relation, self.right, self.line)
+    }
+}
+
+impl Slot {
+    fn parse(text: &str) -> Result<Slot, String> {
+        let (name, value) = match text.find(':') {
+            Some(idx) => (&text[..idx], Some(&text[idx + 1..])),
+            None => (text, None),
+        };
+        if name.starts_with("option_") {
+            let used = match value {
+                None | Some("true") | Some("yes") | Some("on") => true,
+                Some("false") | Some("no") | Some("off") => false,
+                Some(value) => return Err(format!("'{}' isn't true or false", value)),
+            };
+            Ok(Slot::Option { name: name["option_".len()..].to_string(), used })
+        } else {
+            let variants = value.map_or_else(Vec::new, |v| v.split('|').map(|v| v.to_string()).collect());
+            if variants.iter().any(|v| v.is_empty()) {
+                return Err(format!("empty variant in '{}'", text));
+            }
+            Ok(Slot::Template { name: name.trim_end_matches("_back").to_string(), variants })
+        }
+    }
+
+    fn accepts_variant(variants: &[String], variant: Option<&str>) -> bool {
+        match variant {
+            Some(variant) => variants.is_empty() || variants.iter().any(|v| v == variant),
+            None => variants.iter().any(|v| v == "none"),
+        }
+    }
+
+    fn concerns(&self, candidate: Candidate) -> bool {
+        match (self, candidate) {
+            (Slot::Template { name, .. }, Candidate::Template(category, _)) => name == category,
+            (Slot::Option { name, .. }, Candidate::Option(option, _)) => name == option,
+            _ => false,
+        }
+    }
+
+    // Whether the decisions in `recipe` along with `candidate` satisfy the
+    // slot, `None` while it is still undecided. Once `complete`, an undecided
+    // template is empty and an undecided option is unused.
+    fn state(&self, recipe: &FaceRecipe, candidate: Option<Candidate>, complete: bool) -> Option<bool> {
+        match self {
+            Slot::Template { name, variants } => {
+                let mut decided = recipe
+                    .templates
+                    .iter()
+                    .filter(|(key, _)| key.split(':').next() == Some(name.as_str()))
+                    .map(|(_, variant)| variant.as_ref().map(|v| v.as_str()))
+                    .collect::<Vec<_>>();
+                if let Some(Candidate::Template(category, variant)) = candidate {
+                    if category == name {
+                        decided.push(variant);
+                    }
+                }
+                if decided.is_empty() {
+                    return if complete { Some(Slot::accepts_variant(variants, None)) } else { None };
+                }
+                Some(decided.into_iter().any(|variant| Slot::accepts_variant(variants, variant)))
+            }
+            Slot::Option { name, used } => {
+                let decided = match candidate {
+                    Some(Candidate::Option(option, value)) if option == name => Some(value),
+                    _ => recipe.options.get(name).cloned(),
+                };
+                match decided {
+                    Some(value) => Some(value == *used),
+                    None if complete => Some(!*used),
+                    None => None,
+                }
+            }
+        }
+    }
+}
+
+impl ConstraintRule {
+    fn broken(&self, recipe: &FaceRecipe, candidate: Option<Candidate>, complete: bool) -> bool {
+        let left = self.left.state(recipe, candidate, complete);
+        let right = self.right.state(recipe, candidate, complete);
+        match self.relation {
+            Relation::Excludes => left == Some(true) && right == Some(true),
+            Relation::Requires | Relation::Implies => left == Some(true) && right == Some(false),
+        }
+    }
+
+    // Whether deciding `candidate` next would break the rule. The right side
+    // of `requires` is never steered, that's checked once the face is done.
+    fn rejects(&self, recipe: &FaceRecipe, candidate: Candidate) -> bool {
+        let steered = self.left.concerns(candidate) || (self.relation != Relation::Requires && self.right.concerns(candidate));
+        steered && self.broken(recipe, Some(candidate), false)
+    }
+}
+
+impl ConstraintRules {
+    pub fn from_file(path: &Path) -> Result<ConstraintRules, RuleError> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut f| f.read_to_string(&mut text))
+            .map_err(|e| RuleError { line: 0, message: e.to_string() })?;
+        ConstraintRules::parse(&text)
+    }
+
+    pub fn parse(text: &str) -> Result<ConstraintRules, RuleError> {
+        let mut rules = Vec::new();
+        for (idx, line) in text.lines().enumerate() {
+            let line_number = idx + 1;
+            let error = |message: String| RuleError { line: line_number, message };
+            let line = line.split('#').next().unwrap().trim();
+            if line.is_empty() {
+                continue;
+            }
+            let words: Vec<&str> = line.split_whitespace().collect();
+            if words.len() != 3 {
+                return Err(error(format!("expected '<slot> requires|excludes|implies <slot>', not '{}'", line)));
+            }
+            let relation = match words[1] {
+                "requires" => Relation::Requires,
+                "excludes" => Relation::Excludes,
+                "implies" => Relation::Implies,
+                other => return Err(error(format!("unknown relation '{}'", other))),
+            };
+            rules.push(ConstraintRule {
+                left: Slot::parse(words[0]).map_err(error)?,
+                relation,
+                right: Slot::parse(words[2]).map_err(error)?,
+                line: line_number,
+            });
+        }
+        Ok(ConstraintRules { rules })
+    }
+
+    pub fn rules(&self) -> &[ConstraintRule] {
+        &self.rules
+    }
+
+    // The first rule that deciding `candidate` next would break
+    pub fn rejecting(&self, recipe: &FaceRecipe, candidate: Candidate) -> Option<&ConstraintRule> {
+        self.rules.iter().find(|rule| rule.rejects(recipe, candidate))
+    }
+
+    // The first rule deciding `candidate` next would break, on either side
+    pub fn breaking(&self, recipe: &FaceRecipe, candidate: Candidate) -> Option<&ConstraintRule> {
+        self.rules
+            .iter()
+            .find(|rule| (rule.left.concerns(candidate) || rule.right.concerns(candidate)) && rule.broken(recipe, Some(candidate), false))
+    }
+
+    // The first rule a finished face breaks
+    pub fn broken(&self, recipe: &FaceRecipe) -> Option<&ConstraintRule> {
+        self.rules.iter().find(|rule| rule.broken(recipe, None, true))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn template(name: &str, variants: &[&str]) -> Slot {
+        Slot::Template { name: name.to_string(), variants: variants.iter().map(|v| v.to_string()).collect() }
+    }
+
+    fn error(text: &str) -> (usize, String) {
+        let e = ConstraintRules::parse(text).unwrap_err();
+        (e.line, e.message)
+    }
+
+    #[test]
+    fn parses_relations_and_skips_comments() {
+        let text = "# Conical hats sit on bare heads\nhat:conical implies hair:none # for now\n\nnosering requires nose:1|2\nglasses excludes option_eye_bag:false\nhair_back:bob requires option_freckles\n";
+        let rules = ConstraintRules::parse(text).unwrap();
+        let rules = rules.rules();
+        assert_eq!(rules.len(), 4);
+        assert_eq!((&rules[0].left, rules[0].relation, &rules[0].right, rules[0].line), (&template("hat", &["conical"]), Relation::Implies, &template("hair", &["none"]), 2));
+        assert_eq!((&rules[1].left, rules[1].relation, &rules[1].right, rules[1].line), (&template("nosering", &[]), Relation::Requires, &template("nose", &["1", "2"]), 4));
+        assert_eq!(rules[2].relation, Relation::Excludes);
+        assert_eq!(rules[2].right, Slot::Option { name: "eye_bag".to_string(), used: false });
+        assert_eq!(rules[3].left, template("hair", &["bob"]));
+        assert_eq!(rules[3].right, Slot::Option { name: "freckles".to_string(), used: true });
+        assert_eq!(rules[3].to_string(), "hair:bob requires option_freckles (line 6)");
+    }
+
+    #[test]
+    fn errors_have_line_numbers() {
+        assert_eq!(error("hat:conical implies hair:none\nhat conical\n").0, 2);
+        assert_eq!(error("\n\nhat causes hair:none\n"), (3, "unknown relation 'causes'".to_string()));
+        assert_eq!(error("option_nosering:maybe requires nose:1\n"), (1, "'maybe' isn't true or false".to_string()));
+        assert_eq!(error("# ok\nhat:conical| excludes glasses\n"), (2, "empty variant in 'hat:conical|'".to_string()));
+    }
+
+    #[test]
+    fn resolves_candidates_against_recipes() {
+        let rules = ConstraintRules::parse("hat:conical implies hair:none\nglasses excludes option_eye_bag\n").unwrap();
+        let mut recipe = FaceRecipe::default();
+        recipe.templates.insert("hat".to_string(), Some("conical".to_string()));
+        assert!(rules.rejecting(&recipe, Candidate::Template("hair", Some("bob"))).is_some());
+        assert!(rules.rejecting(&recipe, Candidate::Template("hair", None)).is_none());
+        recipe.options.insert("eye_bag".to_string(), true);
+        assert_eq!(rules.rejecting(&recipe, Candidate::Template("glasses", Some("1"))).map(|r| r.line), Some(2));
+        assert!(rules.rejecting(&recipe, Candidate::Template("glasses", None)).is_none());
+        assert!(rules.broken(&recipe).is_none());
+        recipe.templates.insert("hair".to_string(), Some("bob".to_string()));
+        assert_eq!(rules.broken(&recipe).map(|r| r.line), Some(1));
+    }
+}
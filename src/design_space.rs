@@ -0,0 +1,523 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::rc::Rc;
+
+use super::color_scheme;
+use super::weights::Weight;
+use super::Generator;
+
+// A decision still to be made, walked in the same order
+// `Template::generate_from_context` makes them.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+enum Step {
+    Frame { path: String },
+    Template { category: String, variant: String, path: String },
+    Option { path: String, name: String },
+    Guide { path: String, name: String, name_variant: String },
+}
+
+// The remaining steps with the decisions they can still see
+type MemoKey = (Vec<Step>, Vec<(String, String)>);
+
+struct Branch {
+    probability: f64,
+    decision: Option<(String, String)>,
+    steps: Vec<Step>,
+}
+
+// Constraint rules aren't taken into account, faces they reject are still
+// counted.
+#[derive(Clone, Debug, Default)]
+pub struct DesignSpace {
+    // Distinct faces with a non-zero probability. Attributes, palette variants,
+    // templates and options are counted, colors and jitter are not.
+    pub count: u128,
+    // Probability that generation fails because the weights leave no variant
+    pub failure_probability: f64,
+    // Probability of each slot (`species:elf`, `hair_color:red`, `hair:bob`,
+    // `hair:none`, `option_freckles:true`) appearing on a generated face
+    pub marginals: BTreeMap<String, f64>,
+    // Attribute values, palette variants, templates and options no face can have
+    pub dead: Vec<String>,
+}
+
+impl DesignSpace {
+    fn add(&mut self, probability: f64, slots: &[String], outcome: &DesignSpace) {
+        self.count += outcome.count;
+        self.failure_probability += probability * outcome.failure_probability;
+        for slot in slots {
+            self.add_marginal(slot, probability * (1.0 - outcome.failure_probability));
+        }
+        for (slot, p) in &outcome.marginals {
+            self.add_marginal(slot, probability * p);
+        }
+    }
+
+    fn add_marginal(&mut self, slot: &str, probability: f64) {
+        match self.marginals.get_mut(slot) {
+            Some(p) => *p += probability,
+            None => {
+                self.marginals.insert(slot.to_string(), probability);
+            }
+        }
+    }
+}
+
+pub fn measure(generator: &Generator) -> DesignSpace {
+    let mut space = DesignSpace::default();
+    let mut walker = Walker::new(generator);
+    for (attributes, palette, probability, failure) in prefixes(generator) {
+        space.failure_probability += failure;
+        if let Some(palette) = palette {
+            // Weight paths start with the prefix, so nothing carries over
+            walker.weights.clear();
+            walker.memo.clear();
+            let outcome = walker.count(&[first_step(generator, &attributes, &palette)], &BTreeMap::new());
+            let slots: Vec<String> = attributes.iter().chain(palette.iter()).map(|(k, v)| format!("{}:{}", k, v)).collect();
+            space.add(probability, &slots, &outcome);
+        }
+    }
+
+    let mut slots = BTreeSet::new();
+    for axis in generator.attributes.names() {
+        slots.extend(generator.attributes.values(axis).unwrap().keys().map(|v| format!("{}:{}", axis, v)));
+    }
+    for section in generator.palette.sections() {
+        if let Some(variants) = generator.palette.variants(section) {
+            slots.extend(variants.into_iter().map(|v| format!("{}:{}", section, v)));
+        }
+    }
+    for (category, variants) in generator.templates.iter().filter(|(c, _)| !c.ends_with("_back")) {
+        slots.extend(variants.keys().map(|v| format!("{}:{}", category, v)));
+        for template in variants.values() {
+            slots.extend(template.option_names().map(|o| format!("option_{}:true", o)));
+        }
+    }
+    space.dead = slots.into_iter().filter(|s| space.marginals.get(s).map_or(true, |p| *p <= 0.0)).collect();
+    space
+}
+
+// Lists up to `limit` reachable faces as their decisions, keyed like the
+// slots in `DesignSpace::marginals`, with the probability of each.
+pub fn enumerate(generator: &Generator, limit: usize) -> Vec<(BTreeMap<String, String>, f64)> {
+    let mut faces = Vec::new();
+    let mut walker = Walker::new(generator);
+    for (attributes, palette, probability, _) in prefixes(generator) {
+        if faces.len() >= limit {
+            break;
+        }
+        if let Some(palette) = palette {
+            let mut decided: BTreeMap<String, String> = attributes.clone();
+            decided.extend(palette.iter().map(|(k, v)| (k.clone(), v.clone())));
+            walker.weights.clear();
+            walker.enumerate(&[first_step(generator, &attributes, &palette)], &decided, probability, &mut faces, limit);
+        }
+    }
+    faces
+}
+
+// Calls `visit` with every path the generator can look up a weight for under
+// the current weights: the palette variant paths for each combination of
+// attributes, then the template paths once for each combination of attributes
+// and palette variants.
+pub fn weight_paths<F: FnMut(&str)>(generator: &Generator, mut visit: F) {
+    for (attributes, _) in generator.attributes.combinations() {
+        let species = attributes.get("species").map_or("default", |s| s.as_str());
+        let age = attributes.get("age").map_or("default", |s| s.as_str());
+        for path in generator.palette.weight_paths(species, age, &generator.attributes.path(&attributes)) {
+            visit(&path);
+        }
+    }
+    let mut walker = Walker::new(generator);
+    for (attributes, palette, _, _) in prefixes(generator) {
+        if let Some(palette) = palette {
+            walker.weights.clear();
+            walker.explore(&[first_step(generator, &attributes, &palette)], &BTreeMap::new(), &mut HashSet::new());
+            for path in walker.weights.keys() {
+                visit(path);
+            }
+        }
+    }
+}
+
+// Every attribute and palette variant combination with its probability. A
+// combination without a palette carries the probability that palette
+// sampling fails for those attributes.
+fn prefixes(generator: &Generator) -> Vec<(BTreeMap<String, String>, Option<BTreeMap<String, String>>, f64, f64)> {
+    let mut prefixes = Vec::new();
+    for (attributes, probability) in generator.attributes.combinations() {
+        let species = attributes.get("species").map_or("default", |s| s.as_str()).to_string();
+        let age = attributes.get("age").map_or("default", |s| s.as_str()).to_string();
+        let (palettes, failure) = generator.palette.variant_combinations(&species, &age, &generator.weights, &generator.attributes.path(&attributes));
+        if failure > 0.0 {
+            prefixes.push((attributes.clone(), None, 0.0, probability * failure));
+        }
+        for (palette, palette_probability) in palettes {
+            prefixes.push((attributes.clone(), Some(palette), probability * palette_probability, 0.0));
+        }
+    }
+    prefixes
+}
+
+fn first_step(generator: &Generator, attributes: &BTreeMap<String, String>, palette: &BTreeMap<String, String>) -> Step {
+    let species = attributes.get("species").map_or("default", |s| s.as_str());
+    Step::Frame {
+        path: format!("{}:{}", generator.attributes.path(attributes), color_scheme::palette_path(species, palette)),
+    }
+}
+
+fn probability(weight: Weight) -> f64 {
+    match weight {
+        Weight::Always => 1.0,
+        Weight::Sometimes(p) => f64::from(p.max(0.0).min(1.0)),
+    }
+}
+
+struct Walker<'a> {
+    generator: &'a Generator,
+    weights: HashMap<String, Weight>,
+    // Decision keys that can be reached from each template category
+    keys: HashMap<String, BTreeSet<String>>,
+    // Outcomes of already counted walks, keyed by the remaining steps and the
+    // decisions they can still see
+    memo: HashMap<MemoKey, Rc<DesignSpace>>,
+}
+
+impl<'a> Walker<'a> {
+    fn new(generator: &'a Generator) -> Self {
+        let mut keys = HashMap::new();
+        for category in generator.templates.keys() {
+            keys_below(generator, category, &mut keys);
+        }
+        Walker {
+            generator,
+            weights: HashMap::new(),
+            keys,
+            memo: HashMap::new(),
+        }
+    }
+
+    fn weight(&mut self, path: &str) -> Weight {
+        if let Some(weight) = self.weights.get(path) {
+            return *weight;
+        }
+        let weight = self.generator.weights.for_path(path);
+        self.weights.insert(path.to_string(), weight);
+        weight
+    }
+
+    fn count(&mut self, steps: &[Step], decided: &BTreeMap<String, String>) -> Rc<DesignSpace> {
+        if steps.is_empty() {
+            return Rc::new(DesignSpace { count: 1, ..DesignSpace::default() });
+        }
+        let memo_key = self.memo_key(steps, decided);
+        if let Some(outcome) = self.memo.get(&memo_key) {
+            return Rc::clone(outcome);
+        }
+
+        let (branches, failure) = self.branches(&steps[0], decided);
+        let mut outcome = DesignSpace { failure_probability: failure, ..DesignSpace::default() };
+        for branch in branches {
+            let mut decided = decided.clone();
+            let mut slots = Vec::new();
+            if let Some((key, value)) = branch.decision {
+                slots.push(format!("{}:{}", key, value));
+                decided.insert(key, value);
+            }
+            let mut rest = branch.steps;
+            rest.extend_from_slice(&steps[1..]);
+            let rest = self.count(&rest, &decided);
+            outcome.add(branch.probability, &slots, &rest);
+        }
+        let outcome = Rc::new(outcome);
+        self.memo.insert(memo_key, Rc::clone(&outcome));
+        outcome
+    }
+
+    fn explore(&mut self, steps: &[Step], decided: &BTreeMap<String, String>, seen: &mut HashSet<MemoKey>) {
+        if steps.is_empty() || !seen.insert(self.memo_key(steps, decided)) {
+            return;
+        }
+        let (branches, _) = self.branches(&steps[0], decided);
+        for branch in branches {
+            let mut decided = decided.clone();
+            if let Some((key, value)) = branch.decision {
+                decided.insert(key, value);
+            }
+            let mut rest = branch.steps;
+            rest.extend_from_slice(&steps[1..]);
+            self.explore(&rest, &decided, seen);
+        }
+    }
+
+    fn enumerate(&mut self, steps: &[Step], decided: &BTreeMap<String, String>, probability: f64, faces: &mut Vec<(BTreeMap<String, String>, f64)>, limit: usize) {
+        if faces.len() >= limit {
+            return;
+        }
+        if steps.is_empty() {
+            faces.push((decided.clone(), probability));
+            return;
+        }
+        let (branches, _) = self.branches(&steps[0], decided);
+        for branch in branches {
+            let mut decided = decided.clone();
+            if let Some((key, value)) = branch.decision {
+                decided.insert(key, value);
+            }
+            let mut rest = branch.steps;
+            rest.extend_from_slice(&steps[1..]);
+            self.enumerate(&rest, &decided, probability * branch.probability, faces, limit);
+        }
+    }
+
+    // The possible outcomes of `step` and the probability that it fails
+    fn branches(&mut self, step: &Step, decided: &BTreeMap<String, String>) -> (Vec<Branch>, f64) {
+        match step {
+            Step::Template { category, variant, path } => {
+                let template = &self.generator.templates[category][variant];
+                let mut steps: Vec<Step> = template
+                    .option_names()
+                    .map(|name| Step::Option { path: path.clone(), name: name.to_string() })
+                    .collect();
+                // A guide's decision only depends on its path, which is the
+                // same for every guide in this template, so a guide can join an
+                // earlier one with the same key as long as the guides it skips
+                // can't reach anything below it. Keeping shared decisions
+                // together stops them being carried across unrelated guides.
+                let mut guides: Vec<(String, &str, &str)> = Vec::new();
+                for (name, name_variant) in template.guide_names() {
+                    let key = format!("{}{}", front_name(name), name_variant);
+                    let position = guides
+                        .iter()
+                        .rposition(|(k, _, _)| *k == key)
+                        .map(|j| j + 1)
+                        .filter(|j| guides[*j..].iter().all(|(skipped_key, skipped, _)| self.independent(name, skipped_key, skipped)));
+                    let guide = (key, name, name_variant);
+                    match position {
+                        Some(j) => guides.insert(j, guide),
+                        None => guides.push(guide),
+                    }
+                }
+                steps.extend(guides.into_iter().map(|(_, name, name_variant)| Step::Guide {
+                    path: path.clone(),
+                    name: name.to_string(),
+                    name_variant: name_variant.to_string(),
+                }));
+                (vec![Branch { probability: 1.0, decision: None, steps }], 0.0)
+            }
+            Step::Option { path, name } => {
+                let key = format!("option_{}", name);
+                if decided.contains_key(&key) {
+                    return (vec![Branch { probability: 1.0, decision: None, steps: Vec::new() }], 0.0);
+                }
+                let used = probability(self.weight(&format!("{}:option:{}", path, name)));
+                let branches = vec![(true, used), (false, 1.0 - used)]
+                    .into_iter()
+                    .filter(|(_, p)| *p > 0.0)
+                    .map(|(value, p)| Branch { probability: p, decision: Some((key.clone(), value.to_string())), steps: Vec::new() })
+                    .collect();
+                (branches, 0.0)
+            }
+            Step::Frame { path } => {
+                let (choices, failure) = self.choices(&format!("{}:frame", path), "frame");
+                let mut branches = Vec::new();
+                let mut failure = failure;
+                for (variant, p) in choices {
+                    if variant == "none" {
+                        failure += p;
+                    } else {
+                        let steps = vec![Step::Template {
+                            category: "frame".to_string(),
+                            variant: variant.clone(),
+                            path: format!("{}:frame:{}", path, variant),
+                        }];
+                        branches.push(Branch { probability: p, decision: Some(("frame".to_string(), variant)), steps });
+                    }
+                }
+                (branches, failure)
+            }
+            Step::Guide { path, name, name_variant } => {
+                let front = front_name(name);
+                let key = format!("{}{}", front, name_variant);
+                let (choices, failure) = match decided.get(&key) {
+                    Some(variant) => (vec![(variant.clone(), 1.0)], 0.0),
+                    None => self.choices(&format!("{}:{}", path, front), front),
+                };
+                let branches = choices
+                    .into_iter()
+                    .map(|(variant, p)| {
+                        let has_template = self.generator.templates.get(name).map_or(false, |t| t.contains_key(&variant));
+                        let steps = if has_template {
+                            vec![Step::Template {
+                                category: name.clone(),
+                                variant: variant.clone(),
+                                path: format!("{}:{}:{}", path, name, variant),
+                            }]
+                        } else {
+                            Vec::new()
+                        };
+                        let decision = if decided.contains_key(&key) { None } else { Some((key.clone(), variant)) };
+                        Branch { probability: p, decision, steps }
+                    })
+                    .collect();
+                (branches, failure)
+            }
+        }
+    }
+
+    // Mirrors `GenerationContext::choose_variant`, with `none` for an empty guide
+    fn choices(&mut self, full_path: &str, name: &str) -> (Vec<(String, f64)>, f64) {
+        let chosen = probability(self.weight(full_path));
+        let mut choices = Vec::new();
+        let mut none = 1.0 - chosen;
+        let mut failure = 0.0;
+        if chosen > 0.0 {
+            match self.generator.templates.get(name) {
+                Some(variations) => {
+                    let mut variations: Vec<&String> = variations.keys().collect();
+                    variations.sort();
+                    let weights: Vec<(&String, Weight)> = variations
+                        .into_iter()
+                        .map(|v| (v, self.weight(&format!("{}:{}", full_path, v))))
+                        .collect();
+                    if let Some((variation, _)) = weights.iter().find(|(_, w)| *w == Weight::Always) {
+                        choices.push((variation.to_string(), chosen));
+                    } else {
+                        let total: f64 = weights.iter().map(|(_, w)| probability_weight(*w)).sum();
+                        if total <= 0.0 {
+                            failure = chosen;
+                        } else {
+                            for (variation, weight) in weights {
+                                let weight = probability_weight(weight);
+                                if weight > 0.0 {
+                                    choices.push((variation.to_string(), chosen * weight / total));
+                                }
+                            }
+                        }
+                    }
+                }
+                None => none = 1.0,
+            }
+        }
+        if none > 0.0 {
+            choices.insert(0, ("none".to_string(), none));
+        }
+        (choices, failure)
+    }
+
+    // Whether the guide `name` can be walked before the guide `other` without
+    // changing where any decision below it is first made
+    fn independent(&self, name: &str, other_key: &str, other: &str) -> bool {
+        match self.keys.get(name) {
+            Some(below) => !below.contains(other_key) && self.keys.get(other).map_or(true, |other_below| below.is_disjoint(other_below)),
+            None => true,
+        }
+    }
+
+    // Pairs the remaining steps with the decisions they can still see, so
+    // walks that only differ in unrelated decisions share an outcome
+    fn memo_key(&self, steps: &[Step], decided: &BTreeMap<String, String>) -> MemoKey {
+        let mut visible: Vec<&BTreeSet<String>> = Vec::new();
+        let mut own = Vec::new();
+        for step in steps {
+            match step {
+                Step::Frame { .. } => {
+                    own.push("frame".to_string());
+                    visible.push(&self.keys["frame"]);
+                }
+                Step::Template { category, .. } => visible.push(&self.keys[category]),
+                Step::Option { name, .. } => own.push(format!("option_{}", name)),
+                Step::Guide { name, name_variant, .. } => {
+                    own.push(format!("{}{}", front_name(name), name_variant));
+                    if let Some(keys) = self.keys.get(name) {
+                        visible.push(keys);
+                    }
+                }
+            }
+        }
+        let decided = decided
+            .iter()
+            .filter(|(name, _)| own.contains(name) || visible.iter().any(|keys| keys.contains(*name)))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        (steps.to_vec(), decided)
+    }
+}
+
+fn front_name(name: &str) -> &str {
+    if name.ends_with("_back") {
+        &name[..name.len() - 5]
+    } else {
+        name
+    }
+}
+
+// Collects the decision keys reachable below `category` into `keys`
+fn keys_below(generator: &Generator, category: &str, keys: &mut HashMap<String, BTreeSet<String>>) -> BTreeSet<String> {
+    if let Some(below) = keys.get(category) {
+        return below.clone();
+    }
+    keys.insert(category.to_string(), BTreeSet::new());
+    let mut below = BTreeSet::new();
+    if let Some(variants) = generator.templates.get(category) {
+        for template in variants.values() {
+            below.extend(template.option_names().map(|name| format!("option_{}", name)));
+            for (name, name_variant) in template.guide_names() {
+                below.insert(format!("{}{}", front_name(name), name_variant));
+                below.extend(keys_below(generator, name, keys));
+            }
+        }
+    }
+    keys.insert(category.to_string(), below.clone());
+    below
+}
+
+fn probability_weight(weight: Weight) -> f64 {
+    match weight {
+        Weight::Always => 1.0,
+        Weight::Sometimes(w) => f64::from(w),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_assets;
+
+    fn fixture(name: &str) -> Generator {
+        let dir = test_assets::small(name);
+        let generator = Generator::new(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        generator
+    }
+
+    #[test]
+    fn measures_the_fixture() {
+        let space = measure(&fixture("measure"));
+        assert_eq!(space.count, 8);
+        assert_eq!(space.failure_probability, 0.0);
+        assert_eq!(space.dead, vec!["option_freckles:true".to_string()]);
+        for (slot, p) in &[("species:elf", 0.5), ("frame:adult", 1.0), ("skull:long", 0.25), ("option_scar:true", 0.5), ("option_freckles:false", 1.0)] {
+            assert!((space.marginals[*slot] - p).abs() < 1e-9, "{}", slot);
+        }
+    }
+
+    #[test]
+    fn enumerates_the_fixture() {
+        let generator = fixture("enumerate");
+        assert_eq!(enumerate(&generator, 3).len(), 3);
+        let faces = enumerate(&generator, 100);
+        assert_eq!(faces.len() as u128, measure(&generator).count);
+        assert!((faces.iter().map(|(_, p)| p).sum::<f64>() - 1.0).abs() < 1e-9);
+        let distinct: BTreeSet<&BTreeMap<String, String>> = faces.iter().map(|(face, _)| face).collect();
+        assert_eq!(distinct.len(), faces.len());
+        for (face, _) in &faces {
+            let keys: Vec<&str> = face.keys().map(|k| k.as_str()).collect();
+            assert_eq!(keys, vec!["frame", "option_freckles", "option_scar", "skull", "species"]);
+        }
+        let (long, p) = faces.iter().find(|(face, _)| face["skull"] == "long" && face["option_scar"] == "true").unwrap();
+        assert_eq!(long["option_freckles"], "false");
+        assert!((p - 0.5 * 0.25 * 0.5).abs() < 1e-9);
+    }
+}
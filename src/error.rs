@@ -0,0 +1,59 @@
+use std::error::Error;
+use std::fmt;
+
+use super::face_code::FaceCodeError;
+
+#[derive(Debug)]
+pub enum GenerateError {
+    UnknownSlot(String),
+    UnknownTheme(String),
+    // A constraint rule slot naming a palette section or attribute, rules
+    // only relate template categories and options
+    UnsupportedSlot(String),
+    InvalidQuery(String),
+    // Nothing could be chosen at `path`. `constraint` is the lock, query
+    // clause or palette restriction involved, if any, and `rules` are the
+    // weight rules that ruled out the remaining choices.
+    Conflict {
+        constraint: Option<String>,
+        path: String,
+        rules: Vec<String>,
+    },
+    // Every face drawn in `attempts` tries broke the constraint rule `rule`
+    Rejected {
+        rule: String,
+        attempts: usize,
+    },
+    FaceCode(FaceCodeError),
+}
+
+impl fmt::Display for GenerateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            GenerateError::UnknownSlot(slot) => write!(f, "unknown slot '{}'", slot),
+            GenerateError::UnknownTheme(theme) => write!(f, "unknown theme '{}'", theme),
+            GenerateError::UnsupportedSlot(slot) => write!(f, "'{}' isn't a template category or option", slot),
+            GenerateError::InvalidQuery(reason) => write!(f, "invalid query: {}", reason),
+            GenerateError::Conflict { constraint, path, rules } => {
+                match constraint {
+                    Some(constraint) => write!(f, "'{}' can't be satisfied at path '{}'", constraint, path)?,
+                    None => write!(f, "nothing can be chosen at path '{}'", path)?,
+                }
+                if !rules.is_empty() {
+                    write!(f, " because of {}", rules.join(", "))?;
+                }
+                Ok(())
+            }
+            GenerateError::Rejected { rule, attempts } => write!(f, "all {} faces drawn broke '{}'", attempts, rule),
+            GenerateError::FaceCode(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for GenerateError {}
+
+impl From<FaceCodeError> for GenerateError {
+    fn from(e: FaceCodeError) -> Self {
+        GenerateError::FaceCode(e)
+    }
+}
@@ -0,0 +1,80 @@
+use std::fmt;
+
+use super::weights::{Combine, Weight, Weights};
+
+// One call to `Weights::for_path` made while generating a face
+#[derive(Clone, Debug)]
+pub struct Lookup {
+    pub path: String,
+    // File, line number and pattern of the deciding override rule, `None` when
+    // none matched and the weight started out as 1
+    pub source: Option<String>,
+    pub line: Option<usize>,
+    pub pattern: Option<String>,
+    // Multiplying and adding rules that also matched
+    pub modifiers: Vec<String>,
+    pub weight: Weight,
+    // The value drawn against `weight`, if a draw was needed. Variant weights
+    // are drawn from together and have no draw of their own.
+    pub draw: Option<f32>,
+}
+
+impl Lookup {
+    // The rules `weights` applied to reach `weight` for `path`, before anything
+    // is drawn against it
+    pub fn new(weights: &Weights, path: &str, weight: Weight) -> Lookup {
+        let rule = weights.rule_for_path(path);
+        let modifiers = weights
+            .rules_for_path(path)
+            .into_iter()
+            .filter(|r| r.combine != Combine::Override)
+            .map(|r| r.to_string())
+            .collect();
+        Lookup {
+            path: path.to_string(),
+            source: rule.map(|r| r.source.clone()),
+            line: rule.map(|r| r.line),
+            pattern: rule.map(|r| r.regex.to_string()),
+            modifiers,
+            weight,
+            draw: None,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct Explanation {
+    pub lookups: Vec<Lookup>,
+}
+
+impl fmt::Display for Lookup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.path)?;
+        match (&self.source, self.line, &self.pattern) {
+            (Some(source), Some(line), Some(pattern)) => write!(f, "    {} line {}: {}", source, line, pattern)?,
+            _ => write!(f, "    no matching rule")?,
+        }
+        for modifier in &self.modifiers {
+            write!(f, ", {}", modifier)?;
+        }
+        write!(f, " -> {}", self.weight)?;
+        if let Some(draw) = self.draw {
+            let result = if let Weight::Sometimes(prob) = self.weight {
+                if draw < prob { "yes" } else { "no" }
+            } else {
+                "yes"
+            };
+            write!(f, ", drew {:.4} ({})", draw, result)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for lookup in &self.lookups {
+            writeln!(f, "{}", lookup)?;
+        }
+        Ok(())
+    }
+}
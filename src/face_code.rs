@@ -0,0 +1,255 @@
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+// Face codes are lowercase, unpadded base32 (RFC 4648) of:
+//   version: u8, asset fingerprint: u32, seed: u64, attributes: utf8
+// where attributes look like "age=adult;species=dwarf,elf". Version 2 codes
+// follow the attributes with a newline and the query clauses the face was
+// constrained by, e.g. "hair in {bob}; option_freckles=true".
+const VERSION: u8 = 1;
+const QUERY_VERSION: u8 = 2;
+const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+#[derive(Debug, PartialEq)]
+pub enum FaceCodeError {
+    InvalidCharacter(char),
+    Truncated,
+    UnsupportedVersion(u8),
+    AssetMismatch { expected: u32, found: u32 },
+    InvalidAttributes(String),
+}
+
+impl fmt::Display for FaceCodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FaceCodeError::InvalidCharacter(c) => write!(f, "invalid character '{}' in face code", c),
+            FaceCodeError::Truncated => write!(f, "face code is too short"),
+            FaceCodeError::UnsupportedVersion(v) => write!(f, "unsupported face code version {}", v),
+            FaceCodeError::AssetMismatch { expected, found } => write!(
+                f,
+                "face code was made with asset set {:08x} but the loaded assets are {:08x}",
+                found, expected
+            ),
+            FaceCodeError::InvalidAttributes(attributes) => write!(f, "invalid attributes '{}' in face code", attributes),
+        }
+    }
+}
+
+impl Error for FaceCodeError {}
+
+// FNV-1a over every file below the asset directory, visited in sorted order
+// so the fingerprint only changes when the assets do.
+pub fn asset_fingerprint(asset_dir: &Path) -> u32 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let mut feed = |bytes: &[u8]| fnv1a(&mut hash, bytes);
+
+    let mut files = Vec::new();
+    collect_files(asset_dir, asset_dir, &mut files);
+    files.sort();
+    for name in files {
+        feed(name.as_bytes());
+        let mut contents = Vec::new();
+        File::open(asset_dir.join(&name)).unwrap().read_to_end(&mut contents).unwrap();
+        feed(&contents);
+    }
+    (hash ^ (hash >> 32)) as u32
+}
+
+// Folds extra generator settings, like layered probability rules, into a
+// fingerprint so faces made with them get codes of their own
+pub fn extend_fingerprint(fingerprint: u32, bytes: &[u8]) -> u32 {
+    let mut hash = u64::from(fingerprint);
+    fnv1a(&mut hash, bytes);
+    (hash ^ (hash >> 32)) as u32
+}
+
+fn fnv1a(hash: &mut u64, bytes: &[u8]) {
+    for byte in bytes {
+        *hash ^= u64::from(*byte);
+        *hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+}
+
+fn collect_files(root: &Path, dir: &Path, files: &mut Vec<String>) {
+    for entry in dir.read_dir().unwrap() {
+        if let Ok(entry) = entry {
+            let path = entry.path();
+            if path.is_dir() {
+                collect_files(root, &path, files);
+            } else {
+                let name = path.strip_prefix(root).unwrap().to_string_lossy().replace('\\', "/");
+                files.push(name);
+            }
+        }
+    }
+}
+
+pub fn encode(fingerprint: u32, seed: u64, attributes: &HashMap<String, HashSet<String>>, query: &str) -> String {
+    let mut bytes = vec![if query.is_empty() { VERSION } else { QUERY_VERSION }];
+    bytes.extend_from_slice(&fingerprint.to_be_bytes());
+    bytes.extend_from_slice(&seed.to_be_bytes());
+
+    let mut axes: Vec<String> = attributes
+        .iter()
+        .map(|(axis, values)| {
+            let mut values: Vec<&str> = values.iter().map(|v| v.as_str()).collect();
+            values.sort();
+            format!("{}={}", axis, values.join(","))
+        })
+        .collect();
+    axes.sort();
+    bytes.extend_from_slice(axes.join(";").as_bytes());
+    if !query.is_empty() {
+        bytes.push(b'\n');
+        bytes.extend_from_slice(query.as_bytes());
+    }
+
+    base32_encode(&bytes)
+}
+
+// The seed, attributes and query clauses of a face code, the query being
+// empty for version 1 codes
+pub fn decode(fingerprint: u32, code: &str) -> Result<(u64, HashMap<String, HashSet<String>>, String), FaceCodeError> {
+    let bytes = base32_decode(code)?;
+    if bytes.is_empty() {
+        return Err(FaceCodeError::Truncated);
+    }
+    if bytes[0] != VERSION && bytes[0] != QUERY_VERSION {
+        return Err(FaceCodeError::UnsupportedVersion(bytes[0]));
+    }
+    if bytes.len() < 13 {
+        return Err(FaceCodeError::Truncated);
+    }
+
+    let mut found = [0; 4];
+    found.copy_from_slice(&bytes[1..5]);
+    let found = u32::from_be_bytes(found);
+    if found != fingerprint {
+        return Err(FaceCodeError::AssetMismatch { expected: fingerprint, found });
+    }
+
+    let mut seed = [0; 8];
+    seed.copy_from_slice(&bytes[5..13]);
+    let seed = u64::from_be_bytes(seed);
+
+    let text = String::from_utf8(bytes[13..].to_vec())
+        .map_err(|e| FaceCodeError::InvalidAttributes(String::from_utf8_lossy(e.as_bytes()).to_string()))?;
+    let (raw_attributes, query) = match text.find('\n') {
+        Some(idx) if bytes[0] == QUERY_VERSION => (text[..idx].to_string(), text[idx + 1..].to_string()),
+        _ => (text, String::new()),
+    };
+    let mut attributes = HashMap::new();
+    for axis in raw_attributes.split(';').filter(|a| !a.is_empty()) {
+        let eq_idx = axis.find('=').ok_or_else(|| FaceCodeError::InvalidAttributes(raw_attributes.clone()))?;
+        let values = axis[eq_idx + 1..].split(',').map(|v| v.to_string()).collect();
+        attributes.insert(axis[..eq_idx].to_string(), values);
+    }
+    Ok((seed, attributes, query))
+}
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut code = String::with_capacity((bytes.len() * 8 + 4) / 5);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for byte in bytes {
+        buffer = (buffer << 8) | u32::from(*byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            code.push(ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        code.push(ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    code
+}
+
+fn base32_decode(code: &str) -> Result<Vec<u8>, FaceCodeError> {
+    let mut bytes = Vec::with_capacity(code.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in code.trim().chars() {
+        let value = ALPHABET
+            .iter()
+            .position(|a| *a as char == c.to_ascii_lowercase())
+            .ok_or(FaceCodeError::InvalidCharacter(c))?;
+        buffer = (buffer << 5) | value as u32;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes() -> HashMap<String, HashSet<String>> {
+        let mut attributes = HashMap::new();
+        attributes.insert("species".to_string(), ["dwarf", "elf"].iter().map(|v| v.to_string()).collect());
+        attributes.insert("age".to_string(), ["adult"].iter().map(|v| v.to_string()).collect());
+        attributes
+    }
+
+    #[test]
+    fn round_trip() {
+        let code = encode(0xdead_beef, 1234567890123, &attributes(), "");
+        assert_eq!(decode(0xdead_beef, &code), Ok((1234567890123, attributes(), String::new())));
+        assert_eq!(decode(0xdead_beef, &code.to_uppercase()), Ok((1234567890123, attributes(), String::new())));
+        assert_eq!(decode(1, &encode(1, 0, &HashMap::new(), "")), Ok((0, HashMap::new(), String::new())));
+    }
+
+    #[test]
+    fn round_trip_with_query() {
+        let query = "hair in {bob,none}; option_freckles=true";
+        let code = encode(0xdead_beef, 42, &attributes(), query);
+        assert_eq!(decode(0xdead_beef, &code), Ok((42, attributes(), query.to_string())));
+        assert_eq!(decode(0xdead_beef, &encode(0xdead_beef, 42, &HashMap::new(), query)), Ok((42, HashMap::new(), query.to_string())));
+    }
+
+    #[test]
+    fn codes_without_query_are_unchanged() {
+        assert_eq!(base32_decode(&encode(1, 2, &HashMap::new(), "")).unwrap()[0], VERSION);
+    }
+
+    #[test]
+    fn base32_matches_rfc_4648() {
+        assert_eq!(base32_encode(b"foobar"), "mzxw6ytboi");
+        assert_eq!(base32_decode("mzxw6ytboi"), Ok(b"foobar".to_vec()));
+    }
+
+    #[test]
+    fn rejects_other_asset_sets() {
+        let code = encode(0xdead_beef, 7, &attributes(), "");
+        assert_eq!(decode(0x1234_5678, &code), Err(FaceCodeError::AssetMismatch { expected: 0x1234_5678, found: 0xdead_beef }));
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut bytes = vec![QUERY_VERSION + 1];
+        bytes.extend_from_slice(&[0; 12]);
+        assert_eq!(decode(0, &base32_encode(&bytes)), Err(FaceCodeError::UnsupportedVersion(QUERY_VERSION + 1)));
+    }
+
+    #[test]
+    fn rejects_truncated_codes() {
+        let code = encode(0xdead_beef, 7, &HashMap::new(), "");
+        assert_eq!(decode(0xdead_beef, &code[..10]), Err(FaceCodeError::Truncated));
+        assert_eq!(decode(0xdead_beef, ""), Err(FaceCodeError::Truncated));
+    }
+
+    #[test]
+    fn rejects_bad_characters() {
+        let code = encode(0xdead_beef, 7, &HashMap::new(), "");
+        assert_eq!(decode(0xdead_beef, &format!("{}1", &code[..5])), Err(FaceCodeError::InvalidCharacter('1')));
+        assert_eq!(decode(0xdead_beef, "ab-cd"), Err(FaceCodeError::InvalidCharacter('-')));
+    }
+}
@@ -1,6 +1,6 @@
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
-use std::path::{Path, PathBuf};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::Path;
 
 extern crate rand;
 extern crate regex;
@@ -16,11 +16,31 @@ use rand::Rng;
 
 use resvg::svgdom::{AttributeId, AttributeValue, Document, ElementId, Node};
 
+use constraints::{Candidate, ConstraintRule, Slot};
+
+pub use constraints::{ConstraintRules, Constraints};
+pub use error::GenerateError;
+pub use recipe::FaceRecipe;
+
+pub mod attributes;
 pub mod color_scheme;
+pub mod constraints;
+pub mod design_space;
+pub mod error;
+pub mod explain;
+pub mod face_code;
+pub mod lint;
+pub mod query;
+pub mod recipe;
 pub mod template;
+#[cfg(test)]
+mod test_assets;
 pub mod weights;
 
-type Palette = HashMap<String, String>;
+type Palette = HashMap<String, color_scheme::Paint>;
+
+// Faces drawn before giving up on one that keeps breaking constraint rules
+const MAX_ATTEMPTS: usize = 50;
 
 #[derive(Copy, Clone)]
 pub enum Guide {
@@ -146,7 +166,15 @@ pub struct GenerationContext<'a> {
     templates: &'a HashMap<String, HashMap<String, template::Template>>,
     palette: &'a Palette,
     weights: &'a weights::Weights,
-    seeds: RefCell<HashMap<(String, String), u64>>,
+    constraints: &'a Constraints,
+    replay: &'a FaceRecipe,
+    recipe: RefCell<FaceRecipe>,
+    rng: RefCell<StdRng>,
+    error: RefCell<Option<GenerateError>>,
+    trace: Option<&'a RefCell<Vec<explain::Lookup>>>,
+    rules: Option<&'a ConstraintRules>,
+    // The constraint rule that left some decision without choices
+    rejected: RefCell<Option<String>>,
 }
 
 impl<'a> GenerationContext<'a> {
@@ -154,88 +182,268 @@ impl<'a> GenerationContext<'a> {
         templates: &'a HashMap<String, HashMap<String, template::Template>>,
         palette: &'a Palette,
         weights: &'a weights::Weights,
+        constraints: &'a Constraints,
+        replay: &'a FaceRecipe,
+        rng: StdRng,
     ) -> GenerationContext<'a> {
         GenerationContext {
             templates,
             palette,
             weights,
-            seeds: RefCell::new(HashMap::new()),
+            constraints,
+            replay,
+            recipe: RefCell::new(FaceRecipe::default()),
+            rng: RefCell::new(rng),
+            error: RefCell::new(None),
+            trace: None,
+            rules: None,
+            rejected: RefCell::new(None),
+        }
+    }
+
+    // Steers every decision clear of combinations the rules forbid
+    pub fn with_rules(mut self, rules: &'a ConstraintRules) -> Self {
+        self.rules = Some(rules);
+        self
+    }
+
+    // Records every weight lookup and draw made while generating into `trace`
+    pub fn with_trace(mut self, trace: &'a RefCell<Vec<explain::Lookup>>) -> Self {
+        self.trace = Some(trace);
+        self
+    }
+
+    // The constraint rule this face breaks, if any. Such a face is drawn again
+    // rather than rendered.
+    pub fn rejected_rule(&self) -> Option<String> {
+        if let Some(rule) = self.rejected.borrow().clone() {
+            return Some(rule);
+        }
+        self.rules.and_then(|rules| rules.broken(&self.recipe.borrow())).map(|rule| rule.to_string())
+    }
+
+    fn into_rng(self) -> StdRng {
+        self.rng.into_inner()
+    }
+
+    pub fn into_recipe(self) -> Result<FaceRecipe, GenerateError> {
+        match self.error.into_inner() {
+            Some(error) => Err(error),
+            None => Ok(self.recipe.into_inner()),
+        }
+    }
+
+    // Templates only see `Option`s, so the first conflict is stashed here and
+    // reported once generation finishes. The rules reported are the ones
+    // matching `rule_paths`.
+    fn conflict(&self, constraint: Option<String>, path: &str, rule_paths: &[String]) {
+        let mut error = self.error.borrow_mut();
+        if error.is_none() {
+            let mut rules = Vec::new();
+            for rule in rule_paths.iter().flat_map(|p| self.weights.rules_for_path(p)) {
+                let rule = rule.to_string();
+                if !rules.contains(&rule) {
+                    rules.push(rule);
+                }
+            }
+            *error = Some(GenerateError::Conflict {
+                constraint,
+                path: path.to_string(),
+                rules,
+            });
+        }
+    }
+
+    // A decision with nothing left to choose from. When constraint rules
+    // narrowed the choices the face is drawn again, otherwise it's a conflict.
+    fn dead_end(&self, rule: Option<&ConstraintRule>, constraint: Option<String>, path: &str, rule_paths: &[String]) {
+        match rule {
+            Some(rule) => {
+                let mut rejected = self.rejected.borrow_mut();
+                if rejected.is_none() {
+                    *rejected = Some(rule.to_string());
+                }
+            }
+            None => self.conflict(constraint, path, rule_paths),
+        }
+    }
+
+    // The first constraint rule deciding `candidate` next would break
+    fn rejecting_rule(&self, candidate: Candidate) -> Option<&'a ConstraintRule> {
+        self.rules.and_then(|rules| rules.rejecting(&self.recipe.borrow(), candidate))
+    }
+
+    // Replayed decisions aren't steered, so they have to keep clear of every rule
+    fn replay_breaks_rule(&self, candidate: Candidate) -> bool {
+        self.rules.map_or(false, |rules| rules.breaking(&self.recipe.borrow(), candidate).is_some())
+    }
+
+    fn weight(&self, path: &str) -> weights::Weight {
+        let weight = self.weights.for_path(path);
+        if let Some(trace) = self.trace {
+            trace.borrow_mut().push(explain::Lookup::new(self.weights, path, weight));
         }
+        weight
+    }
+
+    // Draws against the weight looked up last
+    fn roll(&self, prob: f32) -> bool {
+        let draw = self.rng.borrow_mut().gen::<f32>();
+        if let Some(trace) = self.trace {
+            if let Some(lookup) = trace.borrow_mut().last_mut() {
+                lookup.draw = Some(draw);
+            }
+        }
+        draw < prob
+    }
+
+    // Jitter is keyed like template choices, so guides sharing a name and
+    // variant share a draw while other guides get their own
+    pub fn jitter(&self, name: &str, name_variant: &str, ranges: &[(f64, f64); 4]) -> [f64; 4] {
+        let key = format!("{}{}", name, name_variant);
+        if let Some(amounts) = self.recipe.borrow().jitter.get(&key) {
+            return *amounts;
+        }
+        let amounts = match self.replay.jitter.get(&key) {
+            Some(amounts) => *amounts,
+            None => {
+                let mut rng = self.rng.borrow_mut();
+                let mut amounts = [0.0; 4];
+                for (amount, (start, end)) in amounts.iter_mut().zip(ranges.iter()) {
+                    if start != end {
+                        *amount = rng.gen_range(*start, *end);
+                    }
+                }
+                amounts
+            }
+        };
+        self.recipe.borrow_mut().jitter.insert(key, amounts);
+        amounts
     }
 
     pub fn use_optional(&self, path: &str, name: &str) -> bool {
+        if let Some(used) = self.recipe.borrow().options.get(name) {
+            return *used;
+        }
         let full_path = format!("{}:option:{}", path, name);
-        let mut base_rng = rand::thread_rng();
-        let seed: u64 = *self
-            .seeds
-            .borrow_mut()
-            .entry((name.to_owned(), "".to_string()))
-            .or_insert_with(|| base_rng.gen());
-        let mut rng: StdRng = SeedableRng::seed_from_u64(seed);
-        let weight = self.weights.for_path(&full_path);
-        match weight {
-            weights::Weight::Always => true,
-            weights::Weight::Sometimes(prob) => rng.gen::<f32>() < prob,
+        let weight = self.weight(&full_path);
+        let locked = self.constraints.options.get(name).cloned();
+        let ruled_out: Vec<(bool, &ConstraintRule)> = [true, false]
+            .iter()
+            .filter_map(|used| self.rejecting_rule(Candidate::Option(name, *used)).map(|rule| (*used, rule)))
+            .collect();
+        let forced = match (locked, ruled_out.as_slice()) {
+            (locked, []) => locked,
+            (Some(used), [(ruled, _)]) if *ruled != used => Some(used),
+            (None, [(ruled, _)]) => Some(!ruled),
+            (_, [(_, rule), ..]) => {
+                self.dead_end(Some(rule), None, &full_path, &[]);
+                return false;
+            }
+        };
+        let used = match (forced, self.replay.options.get(name)) {
+            (Some(used), _) => {
+                if !weight.allows(used) {
+                    let rule = if locked.is_none() { ruled_out.first().map(|(_, rule)| *rule) } else { None };
+                    self.dead_end(rule, locked.map(|_| self.constraints.describe_option(name)), &full_path, &[full_path.clone()]);
+                }
+                used
+            }
+            (None, Some(used)) if weight.allows(*used) && !self.replay_breaks_rule(Candidate::Option(name, *used)) => *used,
+            _ => match weight {
+                weights::Weight::Always => true,
+                weights::Weight::Sometimes(prob) => self.roll(prob),
+            },
+        };
+        self.recipe.borrow_mut().options.insert(name.to_owned(), used);
+        used
+    }
+
+    // Choices for a template category the constraint rules rule out, along
+    // with the rule ruling each out
+    fn ruled_out_variants(&self, name: &str) -> Vec<(Option<String>, &'a ConstraintRule)> {
+        if self.rules.is_none() {
+            return Vec::new();
         }
+        let variations = self.templates.get(name).into_iter().flat_map(|v| v.keys()).map(|v| Some(v.as_str()));
+        variations
+            .chain(Some(None))
+            .filter_map(|v| self.rejecting_rule(Candidate::Template(name, v)).map(|rule| (v.map(|v| v.to_string()), rule)))
+            .collect()
     }
 
-    pub fn choose_template(&self, path: &str, name: &str, name_variant: &str) -> Option<(&template::Template, String)> {
-        let is_back;
-        let name = if name.ends_with("_back") {
-            is_back = true;
-            &name[..name.len() - 5]
+    fn choose_variant(&self, full_path: &str, name: &str) -> Option<String> {
+        let locked = self.constraints.templates.get(name);
+        let ruled_out = self.ruled_out_variants(name);
+        let rule = ruled_out.first().map(|(_, rule)| *rule);
+        let narrowed: BTreeSet<Option<String>>;
+        let allowed = if ruled_out.is_empty() {
+            locked
         } else {
-            is_back = false;
-            name
+            let choices = match locked {
+                Some(locked) => locked.clone(),
+                None => self.templates.get(name).into_iter().flat_map(|v| v.keys()).map(|v| Some(v.to_string())).chain(Some(None)).collect(),
+            };
+            narrowed = choices.into_iter().filter(|v| !ruled_out.iter().any(|(ruled, _)| ruled == v)).collect();
+            Some(&narrowed)
         };
+        let constraint = || locked.map(|_| self.constraints.describe_template(name));
+        if allowed.map_or(false, |a| a.is_empty()) {
+            self.dead_end(rule, constraint(), full_path, &[]);
+            return None;
+        }
+        let may_skip = allowed.map_or(true, |a| a.contains(&None));
+        let may_choose = allowed.map_or(true, |a| a.iter().any(|v| v.is_some()));
 
-        let full_path = format!("{}:{}", path, name);
-        let mut base_rng = rand::thread_rng();
-        let seed: u64 = *self
-            .seeds
-            .borrow_mut()
-            .entry((name.to_owned(), name_variant.to_owned()))
-            .or_insert_with(|| base_rng.gen());
-        let mut rng: StdRng = SeedableRng::seed_from_u64(seed);
-        let prob = self.weights.for_path(&full_path);
-        let do_choose = match prob {
-            weights::Weight::Always => true,
-            weights::Weight::Sometimes(prob) => rng.gen::<f32>() < prob,
+        let prob = self.weight(full_path);
+        let do_choose = if !may_skip || !may_choose {
+            if !prob.allows(may_choose) {
+                self.dead_end(rule, constraint(), full_path, &[full_path.to_string()]);
+                return None;
+            }
+            may_choose
+        } else {
+            match prob {
+                weights::Weight::Always => true,
+                weights::Weight::Sometimes(prob) => self.roll(prob),
+            }
         };
         if do_choose {
             if let Some(variations) = &self.templates.get(name) {
-                let weights: Vec<((&String, &template::Template), weights::Weight)> = variations
-                    .iter()
-                    .map(|v| (v, self.weights.for_path(&format!("{}:{}", full_path, v.0))))
+                let mut variations: Vec<&String> = variations.keys().collect();
+                variations.sort();
+                // One buffer for every variant's path rather than a format each
+                let mut variant_path = format!("{}:", full_path);
+                let prefix = variant_path.len();
+                let weights: Vec<(&String, weights::Weight)> = variations
+                    .into_iter()
+                    .map(|v| {
+                        variant_path.truncate(prefix);
+                        variant_path.push_str(v);
+                        (v, self.weight(&variant_path))
+                    })
                     .collect();
+                let is_allowed = |v: &String| allowed.map_or(true, |a| a.contains(&Some(v.to_string())));
                 let variation;
                 if let Some((choice, _)) = weights.iter().find(|(_, w)| match w { weights::Weight::Always => true, weights::Weight::Sometimes(_) => false}) {
+                    if !is_allowed(choice) {
+                        let path = format!("{}:{}", full_path, choice);
+                        self.dead_end(rule, constraint(), &path, &[path.clone()]);
+                        return None;
+                    }
                     variation = choice;
                 } else {
+                    let weights: Vec<&(&String, weights::Weight)> = weights.iter().filter(|(v, _)| is_allowed(v)).collect();
                     let total_weight:f32 = weights.iter().map(|(_, w)| match w { weights::Weight::Always => panic!(), weights::Weight::Sometimes(w) => w }).sum();
-                    let (choice, _) = weights.choose_weighted(&mut rng, |e| match e.1 { weights::Weight::Always => panic!(), weights::Weight::Sometimes(w) => w } /total_weight).unwrap();
-                    variation = choice;
-                }
-                if is_back {
-                    if self.templates.contains_key(&format!("{}_back", name)) {
-                        let variations = &self.templates[&format!("{}_back", name)];
-                        if variations.contains_key(variation.0) {
-                            Some((
-                                &variations[variation.0],
-                                format!("{}_back:{}", full_path, variation.0),
-                            ))
-                        } else {
-                            None
-                        }
-                    } else {
-                        None
+                    if total_weight <= 0.0 {
+                        let rule_paths: Vec<String> = weights.iter().map(|(v, _)| format!("{}:{}", full_path, v)).collect();
+                        self.dead_end(rule, constraint(), full_path, &rule_paths);
+                        return None;
                     }
-                } else {
-                    Some((
-                        &variations[variation.0],
-                        format!("{}:{}", full_path, variation.0),
-                    ))
+                    let (choice, _) = weights.choose_weighted(&mut *self.rng.borrow_mut(), |e| match e.1 { weights::Weight::Always => panic!(), weights::Weight::Sometimes(w) => w } /total_weight).unwrap();
+                    variation = choice;
                 }
+                Some(variation.to_string())
             } else {
                 eprintln!("No templates for '{}'", name);
                 None
@@ -244,12 +452,84 @@ impl<'a> GenerationContext<'a> {
             None
         }
     }
+
+    // A replayed decision is only honored if the weights for the current path
+    // could have produced it, so inherited or stale choices never break the rules.
+    fn replay_allowed(&self, full_path: &str, name: &str, variation: &Option<String>) -> bool {
+        if let Some(allowed) = self.constraints.templates.get(name) {
+            if !allowed.contains(variation) {
+                return false;
+            }
+        }
+        if self.replay_breaks_rule(Candidate::Template(name, variation.as_ref().map(|v| v.as_str()))) {
+            return false;
+        }
+        let variation = match variation {
+            Some(variation) => variation,
+            None => return self.weights.for_path(full_path).allows(false),
+        };
+        let variations = match self.templates.get(name) {
+            Some(variations) => variations,
+            None => return false,
+        };
+        if !variations.contains_key(variation) || !self.weights.for_path(full_path).allows(true) {
+            return false;
+        }
+        let exclusive = variations
+            .keys()
+            .filter(|v| match self.weights.for_path(&format!("{}:{}", full_path, v)) { weights::Weight::Always => true, weights::Weight::Sometimes(_) => false })
+            .min();
+        match exclusive {
+            Some(exclusive) => exclusive == variation,
+            None => self.weights.for_path(&format!("{}:{}", full_path, variation)).allows(true),
+        }
+    }
+
+    pub fn choose_template(&self, path: &str, name: &str, name_variant: &str) -> Option<(&template::Template, String)> {
+        let is_back;
+        let name = if name.ends_with("_back") {
+            is_back = true;
+            &name[..name.len() - 5]
+        } else {
+            is_back = false;
+            name
+        };
+
+        let full_path = format!("{}:{}", path, name);
+        let key = format!("{}{}", name, name_variant);
+        let recorded = self.recipe.borrow().templates.get(&key).cloned();
+        let variation = match recorded {
+            Some(variation) => variation,
+            None => {
+                let variation = match self.replay.templates.get(&key) {
+                    Some(variation) if self.replay_allowed(&full_path, name, variation) => variation.clone(),
+                    _ => self.choose_variant(&full_path, name),
+                };
+                self.recipe.borrow_mut().templates.insert(key, variation.clone());
+                variation
+            }
+        };
+
+        let variation = variation?;
+        let category = if is_back {
+            format!("{}_back", name)
+        } else {
+            name.to_string()
+        };
+        let template = self.templates.get(&category)?.get(&variation)?;
+        Some((template, format!("{}:{}:{}", path, category, variation)))
+    }
 }
 
 pub struct Generator {
     templates: HashMap<String, HashMap<String, template::Template>>,
-    asset_dir: PathBuf,
+    attributes: attributes::AttributeAxes,
+    palette: color_scheme::PaletteSpec,
+    themes: BTreeMap<String, color_scheme::Theme>,
     weights: weights::Weights,
+    constraint_rules: ConstraintRules,
+    fingerprint: u32,
+    locks: Constraints,
 }
 
 impl Generator {
@@ -267,69 +547,641 @@ impl Generator {
             }
         }
 
-        let weights = weights::Weights::new(&asset_dir.join("probabilities"));
-        let asset_dir = asset_dir.to_owned();
+        let mut weights_path = asset_dir.join("probabilities.jsonl");
+        if !weights_path.exists() {
+            weights_path = asset_dir.join("probabilities");
+        }
+        let weights = weights::Weights::from_file(&weights_path).unwrap_or_else(|e| panic!("{}: {}", weights_path.display(), e));
+        let attributes = attributes::AttributeAxes::from_file(&asset_dir.join("attributes.json"));
+        let palette = color_scheme::PaletteSpec::from_file(&asset_dir.join("palette.json"));
+        let themes_path = asset_dir.join("themes.json");
+        let themes = if themes_path.exists() {
+            color_scheme::themes_from_file(&themes_path).unwrap_or_else(|e| panic!("{}: {}", themes_path.display(), e))
+        } else {
+            BTreeMap::new()
+        };
+        let fingerprint = face_code::asset_fingerprint(asset_dir);
+
+        let mut generator = Self {
+            templates,
+            attributes,
+            palette,
+            themes,
+            weights,
+            constraint_rules: ConstraintRules::default(),
+            fingerprint,
+            locks: Constraints::default(),
+        };
+        let rules_path = asset_dir.join("constraints");
+        if rules_path.exists() {
+            let rules = ConstraintRules::from_file(&rules_path).unwrap_or_else(|e| panic!("{}: {}", rules_path.display(), e));
+            if let Err((line, e)) = generator.check_constraint_rules(&rules) {
+                panic!("{}: line {}: {}", rules_path.display(), line, e);
+            }
+            generator.constraint_rules = rules;
+        }
+        generator
+    }
+
+    // Template classes that palette.json doesn't color, as `category/variant`
+    // and class name. Templates pick their colors only through classes, so
+    // these keep their placeholder colors.
+    pub fn unknown_palette_classes(&self) -> Vec<(String, String)> {
+        let known = self.palette.class_names();
+        let mut unknown = Vec::new();
+        for (category, variants) in &self.templates {
+            for (variant, template) in variants {
+                for class in template.classes().filter(|c| !known.contains(*c)) {
+                    unknown.push((format!("{}/{}", category, variant), class.to_string()));
+                }
+            }
+        }
+        unknown.sort();
+        unknown
+    }
+
+    // Every slot in the rules has to name templates, variants and options
+    // that exist. Palette sections and attributes aren't decided alongside
+    // templates, so rules can't relate them.
+    fn check_constraint_rules(&self, rules: &ConstraintRules) -> Result<(), (usize, GenerateError)> {
+        for rule in rules.rules() {
+            for slot in &[&rule.left, &rule.right] {
+                let mut scratch = Constraints::default();
+                let checked = match slot {
+                    Slot::Template { name, .. } if !self.templates.contains_key(name) => {
+                        Err(if self.palette.variants(name).is_some() || self.attributes.values(name).is_some() {
+                            GenerateError::UnsupportedSlot(name.to_string())
+                        } else {
+                            GenerateError::UnknownSlot(name.to_string())
+                        })
+                    }
+                    Slot::Template { name, variants } => {
+                        let variants: Vec<&str> = variants.iter().map(|v| v.as_str()).collect();
+                        self.constrain(&mut scratch, name, &variants, false)
+                    }
+                    Slot::Option { name, used } => self.constrain(&mut scratch, &format!("option_{}", name), &[if *used { "true" } else { "false" }], false),
+                };
+                checked.map_err(|e| (rule.line, e))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Stacks another probability file over the current rules, so its rules are
+    // tried first. Use it to layer campaign specific odds over the base assets.
+    // Layers are part of the face code fingerprint, so codes only decode with
+    // the same stack.
+    pub fn with_probability_file(mut self, path: &Path) -> Result<Self, weights::RuleError> {
+        let layer = weights::Weights::from_file(path)?;
+        self.fingerprint = face_code::extend_fingerprint(self.fingerprint, layer.to_json_lines().as_bytes());
+        self.weights = self.weights.layer(layer);
+        Ok(self)
+    }
 
-        Self { templates, asset_dir, weights }
+    // Rules that take precedence over every probability file, e.g.
+    // `(".*:hat$".to_string(), Weight::Sometimes(0.8))`
+    pub fn with_weight_overrides(mut self, overrides: Vec<(String, weights::Weight)>) -> Result<Self, weights::RuleError> {
+        let layer = weights::Weights::from_overrides(overrides)?;
+        self.fingerprint = face_code::extend_fingerprint(self.fingerprint, layer.to_json_lines().as_bytes());
+        self.weights = self.weights.layer(layer);
+        Ok(self)
     }
 
-    pub fn generate(&mut self, attributes: &HashMap<String, HashSet<String>>) -> (Document, HashMap<String, String>) {
-        let mut rng = rand::thread_rng();
+    // Pins a slot for every following generation until it is unlocked. Slots
+    // look like `hair:bob`, `beard:none`, `hair_color:red`, `option_freckles`
+    // or `option_freckles:false`.
+    pub fn lock(&mut self, slot: &str) -> Result<(), GenerateError> {
+        let (name, value) = match slot.find(':') {
+            Some(idx) => (&slot[..idx], &slot[idx + 1..]),
+            None if slot.starts_with("option_") => (slot, "true"),
+            None => return Err(GenerateError::UnknownSlot(slot.to_string())),
+        };
+        let mut locks = self.locks.clone();
+        self.constrain(&mut locks, name, &[value], false)?;
+        self.locks = locks;
+        Ok(())
+    }
 
-        let mut base_species = [("human", 0.6), ("dwarf", 0.3), ("elf", 0.3), ("goblin", 0.02), ("cyclops", 0.02)];
-        let mut possible_species = Vec::with_capacity(base_species.len());
-        if attributes.contains_key("species") {
-            let selected_species = &attributes["species"];
-            for (species, weight) in &base_species {
-                if selected_species.contains(&species.to_string()) {
-                    possible_species.push((*species, *weight));
+    fn constrain(&self, constraints: &mut Constraints, name: &str, values: &[&str], negated: bool) -> Result<(), GenerateError> {
+        let unknown = |value: &str| GenerateError::UnknownSlot(format!("{}:{}", name, value));
+        if name.starts_with("option_") {
+            let option = &name["option_".len()..];
+            if !self.templates.values().flat_map(|v| v.values()).any(|t| t.option_names().any(|o| o == option)) {
+                return Err(GenerateError::UnknownSlot(name.to_string()));
+            }
+            let mut allowed = HashSet::new();
+            for value in values {
+                match *value {
+                    "true" | "yes" | "on" => allowed.insert(true),
+                    "false" | "no" | "off" => allowed.insert(false),
+                    _ => return Err(unknown(value)),
+                };
+            }
+            let allowed: Vec<bool> = [true, false].iter().cloned().filter(|v| allowed.contains(v) != negated).collect();
+            match allowed.as_slice() {
+                [used] => constraints.options.insert(option.to_string(), *used),
+                [] => return Err(GenerateError::InvalidQuery(format!("nothing allowed for {}", name))),
+                _ => constraints.options.remove(option),
+            };
+        } else if self.templates.contains_key(name) {
+            let name = name.trim_end_matches("_back");
+            let variations = &self.templates[name];
+            for value in values {
+                if *value != "none" && !variations.contains_key(*value) {
+                    return Err(unknown(value));
+                }
+            }
+            let allowed = variations
+                .keys()
+                .map(|v| Some(v.to_string()))
+                .chain(Some(None))
+                .filter(|v| values.contains(&v.as_ref().map_or("none", |v| v.as_str())) != negated)
+                .collect();
+            constraints.templates.insert(name.to_string(), allowed);
+        } else {
+            let variants = self.palette.variants(name).ok_or_else(|| GenerateError::UnknownSlot(name.to_string()))?;
+            for value in values {
+                if !variants.contains(&value.to_string()) {
+                    return Err(unknown(value));
                 }
             }
+            let allowed = variants
+                .into_iter()
+                .filter(|v| values.contains(&v.as_str()) != negated)
+                .cloned()
+                .collect();
+            constraints.palette.insert(name.to_string(), allowed);
+        }
+        Ok(())
+    }
+
+    // Names of the themes a recipe or a `theme=...` query clause can pick
+    pub fn themes(&self) -> impl Iterator<Item = &str> {
+        self.themes.keys().map(|t| t.as_str())
+    }
+
+    pub fn unlock(&mut self, name: &str) {
+        if name.starts_with("option_") {
+            self.locks.options.remove(&name["option_".len()..]);
         } else {
-            possible_species.append(&mut base_species.iter().cloned().collect())
+            self.locks.templates.remove(name.trim_end_matches("_back"));
+            self.locks.palette.remove(name);
         }
-        let species = possible_species.choose_weighted(&mut rng, |s| s.1).unwrap().0;
+    }
+
+    pub fn clear_locks(&mut self) {
+        self.locks = Constraints::default();
+    }
 
-        let mut base_age = ["child", "adult", "elderly"];
-        let mut possible_age = Vec::with_capacity(base_age.len());
-        if attributes.contains_key("age") {
-            let selected_age = &attributes["age"];
-            for age in &base_age {
-                if selected_age.contains(&age.to_string()) {
-                    possible_age.push(*age);
+    // The code carries the locks in place, so it regenerates the same face
+    // once they are gone
+    pub fn face_code(&self, seed: u64, attributes: &HashMap<String, HashSet<String>>) -> String {
+        face_code::encode(self.fingerprint, seed, attributes, &self.locks.to_query())
+    }
+
+    // The code for the face `generate_query_with_seed` makes, with the locks
+    // in place and the query's clauses and theme
+    pub fn query_face_code(&self, seed: u64, query: &str) -> Result<String, GenerateError> {
+        query::parse(query).map_err(GenerateError::InvalidQuery)?;
+        let locks = self.locks.to_query();
+        let query = match (locks.is_empty(), query.trim().is_empty()) {
+            (_, true) => locks,
+            (true, false) => query.trim().to_string(),
+            (false, false) => format!("{}; {}", locks, query.trim()),
+        };
+        Ok(face_code::encode(self.fingerprint, seed, &HashMap::new(), &query))
+    }
+
+    pub fn decode_face_code(&self, code: &str) -> Result<(u64, HashMap<String, HashSet<String>>, String), face_code::FaceCodeError> {
+        face_code::decode(self.fingerprint, code)
+    }
+
+    // Uses only the constraints stored in the code, not the current locks
+    pub fn generate_from_code(&mut self, code: &str) -> Result<(Document, FaceRecipe), GenerateError> {
+        let (seed, attributes, query) = self.decode_face_code(code)?;
+        self.generate_constrained(seed, attributes, &query, Constraints::default(), None, None)
+    }
+
+    pub fn generate(&mut self, attributes: &HashMap<String, HashSet<String>>) -> Result<(Document, FaceRecipe), GenerateError> {
+        let seed = rand::thread_rng().gen();
+        self.generate_with_seed(seed, attributes)
+    }
+
+    pub fn generate_with_seed(&mut self, seed: u64, attributes: &HashMap<String, HashSet<String>>) -> Result<(Document, FaceRecipe), GenerateError> {
+        self.generate_constrained(seed, attributes.clone(), "", self.locks.clone(), None, None)
+    }
+
+    pub fn generate_in_theme(&mut self, attributes: &HashMap<String, HashSet<String>>, theme: &str) -> Result<(Document, FaceRecipe), GenerateError> {
+        let seed = rand::thread_rng().gen();
+        self.generate_in_theme_with_seed(seed, attributes, theme)
+    }
+
+    // The same face as `generate_with_seed`, rendered in one of `themes()`
+    pub fn generate_in_theme_with_seed(&mut self, seed: u64, attributes: &HashMap<String, HashSet<String>>, theme: &str) -> Result<(Document, FaceRecipe), GenerateError> {
+        self.generate_constrained(seed, attributes.clone(), "", self.locks.clone(), Some(theme), None)
+    }
+
+    pub fn generate_query(&mut self, query: &str) -> Result<(Document, FaceRecipe), GenerateError> {
+        let seed = rand::thread_rng().gen();
+        self.generate_query_with_seed(seed, query)
+    }
+
+    // Queries constrain attribute axes, template categories, options and palette
+    // sections, e.g. `species=dwarf,elf; hair in {bob,crew}; beard=none; eye_color=blue`.
+    // Clauses take precedence over locks on the same slot. `theme=sepia`
+    // renders the face in a theme.
+    pub fn generate_query_with_seed(&mut self, seed: u64, query: &str) -> Result<(Document, FaceRecipe), GenerateError> {
+        self.generate_constrained(seed, HashMap::new(), query, self.locks.clone(), None, None)
+    }
+
+    // Applies the query clauses over `attributes` and `constraints` and
+    // generates a face from `seed`. A `theme=` clause overrides `theme`.
+    fn generate_constrained(&self, seed: u64, mut attributes: HashMap<String, HashSet<String>>, query: &str, mut constraints: Constraints, theme: Option<&str>, trace: Option<&RefCell<Vec<explain::Lookup>>>) -> Result<(Document, FaceRecipe), GenerateError> {
+        let mut theme = theme.map(|t| t.to_string());
+        for clause in query::parse(query).map_err(GenerateError::InvalidQuery)? {
+            let values: Vec<&str> = clause.values.iter().map(|v| v.as_str()).collect();
+            if clause.name == "theme" {
+                if clause.negated || values.len() != 1 {
+                    return Err(GenerateError::InvalidQuery("theme takes exactly one theme".to_string()));
                 }
+                theme = Some(values[0].to_string());
+                continue;
             }
-        } else {
-            possible_age.append(&mut base_age.iter().cloned().collect())
+            match self.attributes.values(&clause.name) {
+                Some(axis) => {
+                    if let Some(value) = values.iter().find(|v| !axis.contains_key(**v)) {
+                        return Err(GenerateError::UnknownSlot(format!("{}:{}", clause.name, value)));
+                    }
+                    let allowed = axis.keys().filter(|v| values.contains(&v.as_str()) != clause.negated).cloned().collect();
+                    attributes.insert(clause.name.to_string(), allowed);
+                }
+                None => self.constrain(&mut constraints, &clause.name, &values, clause.negated)?,
+            }
+        }
+
+        let mut rng: StdRng = SeedableRng::seed_from_u64(seed);
+        let mut recipe = FaceRecipe::default();
+        recipe.attributes = self.attributes.choose(&attributes, &mut rng)?;
+        recipe.theme = theme;
+        self.render_traced(&recipe, &constraints, rng, trace)
+    }
+
+    pub fn offspring(&mut self, a: &FaceRecipe, b: &FaceRecipe, attributes: &HashMap<String, HashSet<String>>, mutation_rate: f32) -> Result<(Document, FaceRecipe), GenerateError> {
+        let seed = rand::thread_rng().gen();
+        self.offspring_with_seed(a, b, attributes, mutation_rate, seed)
+    }
+
+    // The child's own attributes are rolled (species from the parents unless
+    // constrained), every other decision comes from one of the parents and
+    // `mutation_rate` of them are re-rolled.
+    pub fn offspring_with_seed(&mut self, a: &FaceRecipe, b: &FaceRecipe, attributes: &HashMap<String, HashSet<String>>, mutation_rate: f32, seed: u64) -> Result<(Document, FaceRecipe), GenerateError> {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(seed);
+        let mut attributes = attributes.clone();
+        if !attributes.contains_key("species") {
+            let species = [a, b].iter().filter_map(|p| p.attributes.get("species")).cloned().collect();
+            attributes.insert("species".to_string(), species);
+        }
+        let mut child = FaceRecipe::inherit(a, b, &mut rng).forget_decisions(mutation_rate, &mut rng);
+        child.attributes = self.attributes.choose(&attributes, &mut rng)?;
+        self.render(&child, &self.locks, rng)
+    }
+
+    pub fn mutate(&mut self, recipe: &FaceRecipe, rate: f32) -> Result<(Document, FaceRecipe), GenerateError> {
+        let seed = rand::thread_rng().gen();
+        self.mutate_with_seed(recipe, rate, seed)
+    }
+
+    pub fn mutate_with_seed(&mut self, recipe: &FaceRecipe, rate: f32, seed: u64) -> Result<(Document, FaceRecipe), GenerateError> {
+        let mut rng: StdRng = SeedableRng::seed_from_u64(seed);
+        let partial = recipe.forget_decisions(rate, &mut rng);
+        self.render(&partial, &self.locks, rng)
+    }
+
+    // Replays every recorded decision, locks don't apply. Set `recipe.theme`
+    // to render the same face in another theme.
+    pub fn render_recipe(&self, recipe: &FaceRecipe) -> Result<Document, GenerateError> {
+        Ok(self.replay(recipe)?.0)
+    }
+
+    fn replay(&self, recipe: &FaceRecipe) -> Result<(Document, FaceRecipe), GenerateError> {
+        self.render(recipe, &Constraints::default(), SeedableRng::seed_from_u64(0))
+    }
+
+    // Generated faces carry their palette in `recipe.resolved_palette`. This
+    // resolves it again, e.g. after setting `recipe.theme`.
+    pub fn resolved_palette(&self, recipe: &FaceRecipe) -> Result<color_scheme::ResolvedPalette, GenerateError> {
+        Ok(self.palette.resolve(&recipe.palette, self.theme(recipe)?))
+    }
+
+    fn theme(&self, recipe: &FaceRecipe) -> Result<Option<&color_scheme::Theme>, GenerateError> {
+        match &recipe.theme {
+            Some(name) => self.themes.get(name).map(Some).ok_or_else(|| GenerateError::UnknownTheme(name.to_string())),
+            None => Ok(None),
         }
-        let age = possible_age.choose(&mut rng).unwrap();
+    }
+
+    // Generates the same face as `generate_with_seed` along with every weight
+    // lookup made for it, to find out why a feature does or doesn't show up
+    pub fn explain(&mut self, seed: u64, attributes: &HashMap<String, HashSet<String>>) -> Result<(Document, FaceRecipe, explain::Explanation), GenerateError> {
+        let trace = RefCell::new(Vec::new());
+        let (document, recipe) = self.generate_constrained(seed, attributes.clone(), "", self.locks.clone(), None, Some(&trace))?;
+        Ok((document, recipe, explain::Explanation { lookups: trace.into_inner() }))
+    }
+
+    // Explains the face `generate_from_code` makes from a code
+    pub fn explain_code(&mut self, code: &str) -> Result<(Document, FaceRecipe, explain::Explanation), GenerateError> {
+        let (seed, attributes, query) = self.decode_face_code(code)?;
+        let trace = RefCell::new(Vec::new());
+        let (document, recipe) = self.generate_constrained(seed, attributes, &query, Constraints::default(), None, Some(&trace))?;
+        Ok((document, recipe, explain::Explanation { lookups: trace.into_inner() }))
+    }
+
+    fn render(&self, replay: &FaceRecipe, constraints: &Constraints, rng: StdRng) -> Result<(Document, FaceRecipe), GenerateError> {
+        self.render_traced(replay, constraints, rng, None)
+    }
+
+    // Faces breaking a constraint rule are thrown away and drawn again with
+    // the same palette, carrying on with the same random numbers. The trace
+    // keeps the palette's lookups and the last attempt's.
+    fn render_traced(&self, replay: &FaceRecipe, constraints: &Constraints, mut rng: StdRng, trace: Option<&RefCell<Vec<explain::Lookup>>>) -> Result<(Document, FaceRecipe), GenerateError> {
+        let species = replay.attributes.get("species").map_or("default", |s| s.as_str());
+        let age = replay.attributes.get("age").map_or("default", |s| s.as_str());
 
-        let (palette_path, palette) = &color_scheme::palette_from_file(&self.asset_dir.join("palette.json"), species, age);
-        let context = GenerationContext::new(&self.templates, &palette, &self.weights);
+        let theme = self.theme(replay)?;
 
-        let mut base_sex = ["male", "female"];
-        let mut possible_sex = Vec::with_capacity(base_sex.len());
-        if attributes.contains_key("sex") {
-            let selected_sex = &attributes["sex"];
-            for sex in &base_sex {
-                if selected_sex.contains(&sex.to_string()) {
-                    possible_sex.push(*sex);
+        let attributes_path = self.attributes.path(&replay.attributes);
+        if let Some(trace) = trace {
+            trace.borrow_mut().clear();
+        }
+        let (palette_path, palette_choices) = self.palette.sample(species, age, constraints, &replay.palette, &self.weights, &attributes_path, trace, &mut rng)?;
+        let palette_lookups = trace.map_or(0, |trace| trace.borrow().len());
+        let palette = self.palette.palette_from_choices(&palette_choices, theme);
+        let full_path = format!("{}:{}", attributes_path, palette_path);
+        let mut attempts = 0;
+        loop {
+            attempts += 1;
+            let mut context = GenerationContext::new(&self.templates, &palette, &self.weights, constraints, replay, rng).with_rules(&self.constraint_rules);
+            if let Some(trace) = trace {
+                trace.borrow_mut().truncate(palette_lookups);
+                context = context.with_trace(trace);
+            }
+
+            let document = context
+                .choose_template(&full_path, "frame", "")
+                .map(|(frame, frame_path)| frame.generate_from_context(&context, &frame_path));
+            match context.rejected_rule() {
+                Some(_) if attempts < MAX_ATTEMPTS => {
+                    rng = context.into_rng();
+                    continue;
                 }
+                Some(rule) => return Err(GenerateError::Rejected { rule, attempts }),
+                None => {}
             }
-        } else {
-            possible_sex.append(&mut base_sex.iter().cloned().collect())
+            let document = match document {
+                Some(document) => document,
+                None => {
+                    let frame_path = format!("{}:frame", full_path);
+                    context.conflict(None, &frame_path, &[frame_path.clone()]);
+                    return Err(context.into_recipe().unwrap_err());
+                }
+            };
+
+            let mut recipe = context.into_recipe()?;
+            recipe.attributes = replay.attributes.clone();
+            recipe.resolved_palette = self.palette.resolve(&palette_choices, theme);
+            recipe.palette = palette_choices;
+            recipe.theme = replay.theme.clone();
+            return Ok((document, recipe));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator() -> Generator {
+        Generator::new(&Path::new(env!("CARGO_MANIFEST_DIR")).join("assets"))
+    }
+
+    fn error(result: Result<(Document, FaceRecipe), GenerateError>) -> String {
+        match result {
+            Ok(_) => "ok".to_string(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn seeds_give_identical_svgs() {
+        let mut generator = generator();
+        let attributes = HashMap::new();
+        for seed in 0..5 {
+            let (first, _) = generator.generate_with_seed(seed, &attributes).unwrap();
+            let (second, _) = generator.generate_with_seed(seed, &attributes).unwrap();
+            assert_eq!(first.to_string(), second.to_string());
         }
-        let sex = possible_sex.choose(&mut rng).unwrap();
+    }
+
+    #[test]
+    fn recipes_render_the_original_svg() {
+        let mut generator = generator();
+        for seed in 0..5 {
+            let (document, recipe) = generator.generate_with_seed(seed, &HashMap::new()).unwrap();
+            let saved = FaceRecipe::from_json(&recipe.to_json()).unwrap();
+            assert_eq!(saved, recipe);
+            assert_eq!(generator.render_recipe(&saved).unwrap().to_string(), document.to_string());
+        }
+    }
 
-        let full_path = format!(":species:{}:age:{}:sex:{}:{}", species, age, sex, palette_path);
-        let mut choices = HashMap::new();
-        choices.insert("species".to_string(), species.to_string());
-        choices.insert("age".to_string(), age.to_string());
-        choices.insert("sex".to_string(), sex.to_string());
+    #[test]
+    fn mutation_rerolls_a_fraction_of_decisions() {
+        let mut generator = generator();
+        let (_, recipe) = generator.generate_with_seed(4, &HashMap::new()).unwrap();
+        assert_eq!(generator.mutate_with_seed(&recipe, 0.0, 1).unwrap().1, recipe);
+        let kept = |mutated: &FaceRecipe| recipe.templates.iter().filter(|(guide, variant)| mutated.templates.get(*guide) == Some(variant)).count();
+        let mut changed = 0;
+        for seed in 0..10 {
+            let (_, mutated) = generator.mutate_with_seed(&recipe, 0.3, seed).unwrap();
+            assert_eq!(mutated.attributes, recipe.attributes);
+            assert!(kept(&mutated) >= recipe.templates.len() / 2);
+            changed += (mutated != recipe) as usize;
+        }
+        assert!(changed > 0);
+    }
 
-        let (frame, full_path) = context
-            .choose_template(&format!("{}:{}", full_path, sex), "frame", "")
-            .unwrap();
-        (frame.generate_from_context(&context, &full_path), choices)
+    #[test]
+    fn offspring_replay_their_parents() {
+        let mut generator = generator();
+        let attributes: HashMap<String, HashSet<String>> = [("species", "human"), ("age", "adult"), ("sex", "female")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), [v.to_string()].iter().cloned().collect()))
+            .collect();
+        let (_, a) = generator.generate_with_seed(5, &attributes).unwrap();
+        let (_, b) = generator.generate_with_seed(6, &attributes).unwrap();
+        let (_, clone) = generator.offspring_with_seed(&a, &a, &attributes, 0.0, 1).unwrap();
+        assert_eq!((&clone.templates, &clone.options, &clone.palette), (&a.templates, &a.options, &a.palette));
+        for seed in 0..5 {
+            let (_, child) = generator.offspring_with_seed(&a, &b, &attributes, 0.0, seed).unwrap();
+            assert_eq!(child.attributes, a.attributes);
+            for (guide, variant) in &child.templates {
+                if a.templates.contains_key(guide) || b.templates.contains_key(guide) {
+                    assert!(a.templates.get(guide) == Some(variant) || b.templates.get(guide) == Some(variant), "{}", guide);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn weight_overrides_beat_the_probability_file() {
+        let path = ":species:human:age:adult:sex:female:skull:human:glasses";
+        let generator = generator();
+        assert_eq!(generator.weights.for_path(path), weights::Weight::Sometimes(0.0));
+        let generator = generator.with_weight_overrides(vec![(".*:glasses$".to_string(), weights::Weight::Sometimes(0.5))]).unwrap();
+        assert_eq!(generator.weights.for_path(path), weights::Weight::Sometimes(0.5));
+    }
+
+    #[test]
+    fn query_rejects_unknown_slots() {
+        let mut generator = generator();
+        assert_eq!(error(generator.generate_query_with_seed(1, "hairz=bob")), "unknown slot 'hairz'");
+        assert_eq!(error(generator.generate_query_with_seed(1, "hair in {bob,nope}")), "unknown slot 'hair:nope'");
+        assert_eq!(error(generator.generate_query_with_seed(1, "species=martian")), "unknown slot 'species:martian'");
+        assert_eq!(error(generator.generate_query_with_seed(1, "option_nope=true")), "unknown slot 'option_nope'");
+        assert_eq!(error(generator.generate_query_with_seed(1, "theme=nope")), "unknown theme 'nope'");
+        assert_eq!(error(generator.generate_query_with_seed(1, "theme=sepia,night")), "invalid query: theme takes exactly one theme");
+    }
+
+    #[test]
+    fn themes_can_be_picked_without_a_query() {
+        let mut generator = generator();
+        let (_, plain) = generator.generate_with_seed(4, &HashMap::new()).unwrap();
+        let (_, themed) = generator.generate_in_theme_with_seed(4, &HashMap::new(), "sepia").unwrap();
+        assert_eq!(themed.theme, Some("sepia".to_string()));
+        assert_eq!(themed.resolved_palette.variants, plain.resolved_palette.variants);
+        assert_ne!(themed.resolved_palette.colors, plain.resolved_palette.colors);
+        assert_eq!(generator.resolved_palette(&themed).unwrap(), themed.resolved_palette);
+        assert_eq!(FaceRecipe { theme: None, resolved_palette: plain.resolved_palette.clone(), ..themed }, plain);
+        assert_eq!(error(generator.generate_in_theme_with_seed(4, &HashMap::new(), "nope")), "unknown theme 'nope'");
+    }
+
+    #[test]
+    fn query_face_codes_reproduce_the_face() {
+        let mut generator = generator();
+        generator.lock("option_eye_bag:false").unwrap();
+        let query = "species=elf; eye_color!=blue; theme=sepia";
+        let (_, recipe) = generator.generate_query_with_seed(3, query).unwrap();
+        let code = generator.query_face_code(3, query).unwrap();
+        generator.clear_locks();
+        let (_, decoded) = generator.generate_from_code(&code).unwrap();
+        assert_eq!(decoded, recipe);
+        assert_eq!(decoded.theme, Some("sepia".to_string()));
+        assert_eq!(decoded.options.get("eye_bag"), Some(&false));
+    }
+
+    #[test]
+    fn face_codes_carry_locks() {
+        let mut generator = generator();
+        generator.lock("clothing_main_color:black").unwrap();
+        let (_, recipe) = generator.generate_with_seed(9, &HashMap::new()).unwrap();
+        let code = generator.face_code(9, &HashMap::new());
+        generator.clear_locks();
+        assert_eq!(generator.generate_from_code(&code).unwrap().1, recipe);
+        assert_eq!(recipe.palette["clothing_main_color"].variant, "black");
+    }
+
+    #[test]
+    fn render_recipe_ignores_locks() {
+        let mut generator = generator();
+        let (_, recipe) = generator.generate_with_seed(9, &HashMap::new()).unwrap();
+        let other = if recipe.palette["clothing_main_color"].variant == "grey" { "black" } else { "grey" };
+        generator.lock(&format!("clothing_main_color:{}", other)).unwrap();
+        generator.lock("option_eye_bag").unwrap();
+        assert_eq!(generator.replay(&recipe).unwrap().1, recipe);
+    }
+
+    fn with_rules(generator: &mut Generator, text: &str) -> Result<(), String> {
+        let rules = ConstraintRules::parse(text).map_err(|e| e.to_string())?;
+        generator.check_constraint_rules(&rules).map_err(|(line, e)| format!("line {}: {}", line, e))?;
+        generator.constraint_rules = rules;
+        Ok(())
+    }
+
+    // Variants chosen for every guide of `category` in `recipe`
+    fn chosen<'a>(recipe: &'a FaceRecipe, category: &str) -> Vec<Option<&'a str>> {
+        recipe
+            .templates
+            .iter()
+            .filter(|(guide, _)| guide.split(':').next() == Some(category))
+            .map(|(_, variant)| variant.as_ref().map(|v| v.as_str()))
+            .collect()
+    }
+
+    fn faces(generator: &mut Generator) -> Vec<FaceRecipe> {
+        (0..40).map(|seed| generator.generate_with_seed(seed, &HashMap::new()).unwrap().1).collect()
+    }
+
+    #[test]
+    fn constraint_rules_only_relate_templates_and_options() {
+        let mut generator = generator();
+        let rules = "hat:conical implies hair:none\n\nhair_color:red excludes hat:conical\n";
+        assert_eq!(with_rules(&mut generator, rules), Err("line 3: 'hair_color' isn't a template category or option".to_string()));
+        assert_eq!(with_rules(&mut generator, "species:elf requires hat"), Err("line 1: 'species' isn't a template category or option".to_string()));
+        assert_eq!(with_rules(&mut generator, "hat implies hairz:none"), Err("line 1: unknown slot 'hairz'".to_string()));
+        assert_eq!(with_rules(&mut generator, "hat:sombrero implies hair:none"), Err("line 1: unknown slot 'hat:sombrero'".to_string()));
+        assert_eq!(with_rules(&mut generator, "option_nope excludes hat"), Err("line 1: unknown slot 'option_nope'".to_string()));
+        assert_eq!(with_rules(&mut generator, "hair_back:bob requires option_eye_bag:false"), Ok(()));
+    }
+
+    #[test]
+    fn implied_slots_are_forced() {
+        let mut generator = generator();
+        generator.lock("hat:conical").unwrap();
+        let unruled = faces(&mut generator);
+        with_rules(&mut generator, "hat:conical implies hair:bob").unwrap();
+        let ruled = faces(&mut generator);
+        let bob = |r: &FaceRecipe| chosen(r, "hair") == vec![Some("bob")];
+        let conical = |r: &FaceRecipe| chosen(r, "hat").contains(&Some("conical"));
+        assert!(unruled.iter().any(|r| conical(r) && !bob(r)));
+        assert!(ruled.iter().any(|r| conical(r)));
+        assert!(ruled.iter().filter(|r| conical(r)).all(|r| bob(r)));
+    }
+
+    #[test]
+    fn excluded_slots_never_meet() {
+        let mut generator = generator().with_weight_overrides(vec![(".*:glasses$".to_string(), weights::Weight::Sometimes(0.5))]).unwrap();
+        with_rules(&mut generator, "glasses excludes option_eye_bag").unwrap();
+        let faces = faces(&mut generator);
+        let glasses = |r: &FaceRecipe| chosen(r, "glasses").iter().any(|v| v.is_some());
+        let eye_bags = |r: &FaceRecipe| r.options.get("eye_bag") == Some(&true);
+        assert!(faces.iter().any(|r| glasses(r)) && faces.iter().any(|r| eye_bags(r)));
+        assert!(!faces.iter().any(|r| glasses(r) && eye_bags(r)));
+    }
+
+    #[test]
+    fn faces_breaking_requirements_are_drawn_again() {
+        let mut generator = generator();
+        let unruled = faces(&mut generator);
+        with_rules(&mut generator, "hair:bob|crew requires option_eye_bag:false").unwrap();
+        let ruled = faces(&mut generator);
+        let breaks = |r: &FaceRecipe| chosen(r, "hair").iter().any(|v| *v == Some("bob") || *v == Some("crew")) && r.options.get("eye_bag") == Some(&true);
+        assert!(unruled.iter().any(|r| breaks(r)));
+        assert!(!ruled.iter().any(|r| breaks(r)));
+        assert!(ruled.iter().zip(&unruled).any(|(a, b)| a == b));
+    }
+
+    #[test]
+    fn explanations_include_palette_lookups() {
+        let mut generator = generator();
+        with_rules(&mut generator, "hair:bob|crew requires option_eye_bag:false").unwrap();
+        for seed in 0..10 {
+            let (_, recipe, explanation) = generator.explain(seed, &HashMap::new()).unwrap();
+            let paths: Vec<&str> = explanation.lookups.iter().map(|l| l.path.as_str()).collect();
+            let first_template = paths.iter().position(|p| !p.contains(":palette:")).unwrap();
+            assert!(paths[first_template..].iter().all(|p| !p.contains(":palette:")));
+            for (section, choice) in &recipe.palette {
+                let path = format!(":palette:{}:{}", section, choice.variant);
+                assert_eq!(paths[..first_template].iter().filter(|p| p.ends_with(&path)).count(), 1);
+            }
+        }
     }
 }
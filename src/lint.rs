@@ -0,0 +1,344 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use regex::RegexSet;
+
+use super::design_space;
+use super::weights::{Combine, Rule, Weight};
+use super::Generator;
+
+#[derive(Debug, PartialEq)]
+pub struct Problem {
+    // File of the rule the problem is with, rules come from layered files
+    pub source: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl Problem {
+    fn new(rule: &Rule, message: String) -> Self {
+        Problem { source: rule.source.clone(), line: rule.line, message }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", location(&self.source, self.line), self.message)
+    }
+}
+
+fn location(source: &str, line: usize) -> String {
+    if source.is_empty() {
+        format!("line {}", line)
+    } else {
+        format!("{} line {}", source, line)
+    }
+}
+
+// Checks the loaded weight rules against the assets and against the paths the
+// generator actually looks up. Problems come in the order the rules are tried.
+pub fn lint(generator: &Generator) -> Vec<Problem> {
+    let names = Names::new(generator);
+    let mut problems = Vec::new();
+    for (i, rule) in generator.weights.rules().iter().enumerate() {
+        if let (Combine::Override, Weight::Sometimes(prob)) = (rule.combine, rule.weight) {
+            if prob > 1.0 {
+                problems.push((i, Problem::new(rule, format!("probability {} is above 1.0", prob))));
+            }
+        }
+        for message in names.check(rule.regex.as_str()) {
+            problems.push((i, Problem::new(rule, message)));
+        }
+    }
+    problems.extend(check_firing(generator));
+    problems.sort_by_key(|(i, _)| *i);
+    problems.into_iter().map(|(_, problem)| problem).collect()
+}
+
+// Template classes palette.json doesn't color, which keep their placeholder
+// colors, then palette sections no template class refers to and tones or
+// gradients that are neither used by a template nor as a gradient stop.
+// Outlines every section gets aren't reported unless palette.json declares them.
+pub fn palette_problems(generator: &Generator) -> Vec<String> {
+    let mut problems: Vec<String> = generator
+        .unknown_palette_classes()
+        .into_iter()
+        .map(|(template, class)| format!("{}: class '{}' has no section, tone or outline", template, class))
+        .collect();
+    let mut used: BTreeSet<&str> = generator.templates.values().flat_map(|v| v.values()).flat_map(|t| t.classes()).collect();
+    used.extend(generator.palette.gradient_stop_names());
+    for section in generator.palette.sections() {
+        let declared = generator.palette.declared_names(section);
+        let outline = format!("{}_outline", section);
+        if !used.contains(section) && !used.contains(outline.as_str()) && declared.iter().all(|d| !used.contains(d.as_str())) {
+            problems.push(format!("palette section '{}' isn't used by any template", section));
+            continue;
+        }
+        for name in declared.iter().filter(|d| !used.contains(d.as_str())) {
+            problems.push(format!("'{}' isn't used by any template or gradient", name));
+        }
+    }
+    problems
+}
+
+// Override rules are tried in order and the first match wins, so one only ever
+// decides a weight if some path reaches it before any earlier override rule
+// matches. Multiplying and adding rules apply whenever they match.
+fn check_firing(generator: &Generator) -> Vec<(usize, Problem)> {
+    let rules = generator.weights.rules();
+    let set = RegexSet::new(rules.iter().map(|r| r.regex.as_str())).unwrap();
+    let mut fired = vec![false; rules.len()];
+    let mut shadowed_by = vec![BTreeSet::new(); rules.len()];
+    design_space::weight_paths(generator, |path| {
+        let mut first = None;
+        for i in set.matches(path).iter() {
+            match (rules[i].combine, first) {
+                (Combine::Override, Some(first)) => {
+                    shadowed_by[i].insert(first);
+                }
+                (Combine::Override, None) => {
+                    first = Some(i);
+                    fired[i] = true;
+                }
+                _ => fired[i] = true,
+            }
+        }
+    });
+
+    let mut problems = Vec::new();
+    for (i, rule) in rules.iter().enumerate().filter(|(i, _)| !fired[*i]) {
+        let message = if shadowed_by[i].is_empty() {
+            format!("`{}` matches no path the generator looks up", rule.regex)
+        } else {
+            let earlier: Vec<String> = shadowed_by[i].iter().map(|e| format!("{} `{}`", location(&rules[*e].source, rules[*e].line), rules[*e].regex)).collect();
+            format!("`{}` never fires, its paths are all matched first by {}", rule.regex, earlier.join(", "))
+        };
+        problems.push((i, Problem::new(rule, message)));
+    }
+    problems
+}
+
+// Everything a rule can refer to: template categories, variants and options,
+// attribute axes and palette sections, each with the values that can follow
+// them in a path
+struct Names {
+    values: BTreeMap<String, (String, BTreeSet<String>)>,
+    segments: BTreeSet<String>,
+}
+
+impl Names {
+    fn new(generator: &Generator) -> Self {
+        let mut values = BTreeMap::new();
+        let mut options = BTreeSet::new();
+        for (category, variants) in &generator.templates {
+            values.insert(category.to_string(), (format!("{} variant", category), variants.keys().cloned().collect()));
+            for template in variants.values() {
+                options.extend(template.option_names().map(|o| o.to_string()));
+            }
+        }
+        values.insert("option".to_string(), ("option".to_string(), options));
+        for axis in generator.attributes.names() {
+            let axis_values = generator.attributes.values(axis).unwrap().keys().cloned().collect();
+            values.insert(axis.to_string(), (format!("{} value", axis), axis_values));
+        }
+        for section in generator.palette.sections() {
+            let variants = generator.palette.variants(section).map_or_else(BTreeSet::new, |v| v.into_iter().cloned().collect());
+            values.insert(section.to_string(), (format!("{} variant", section), variants));
+        }
+
+        let mut segments: BTreeSet<String> = values.keys().cloned().collect();
+        segments.insert("palette".to_string());
+        for (_, names) in values.values() {
+            segments.extend(names.iter().cloned());
+        }
+        Names { values, segments }
+    }
+
+    // Looks at the literal names in a pattern. A name after `category:`,
+    // `option:`, an axis or a palette section has to be one of its values, a
+    // name right before `$` has to end a template, variant or option name and
+    // any other name has to appear somewhere in the assets.
+    fn check(&self, pattern: &str) -> Vec<String> {
+        let runs = literal_runs(pattern);
+        let mut problems = Vec::new();
+        for (i, run) in runs.iter().enumerate() {
+            let owner = if i > 0 && run.before == Some(':') && runs[i - 1].end + 1 == run.start {
+                self.values.get(&runs[i - 1].text)
+            } else {
+                None
+            };
+            let starts_segment = run.before == Some(':') || run.before == Some('^');
+            let ends_segment = run.after == Some(':') || run.after == Some('$');
+            if run.after.map_or(false, |a| "?*+{".contains(a)) {
+                // The last character is optional, too loose to check
+                continue;
+            }
+            if let Some((kind, values)) = owner {
+                let known = values.iter().any(|v| if ends_segment { *v == run.text } else { v.starts_with(&run.text) });
+                if !known {
+                    problems.push(format!("unknown {} '{}'", kind, run.text));
+                }
+            } else if run.after == Some('$') {
+                let known = self.segments.iter().any(|s| if starts_segment { *s == run.text } else { s.ends_with(&run.text) });
+                if !known {
+                    problems.push(format!("'{}' doesn't end any template, variant or option name", run.text));
+                }
+            } else if starts_segment && ends_segment {
+                if !self.segments.contains(&run.text) {
+                    problems.push(format!("'{}' doesn't name anything in the assets", run.text));
+                }
+            } else if !self.segments.iter().any(|s| s.contains(&run.text)) {
+                problems.push(format!("'{}' doesn't appear in any name in the assets", run.text));
+            }
+        }
+        problems
+    }
+}
+
+struct Run {
+    text: String,
+    start: usize,
+    end: usize,
+    before: Option<char>,
+    after: Option<char>,
+}
+
+// Runs of literal name characters in a regex, skipping escapes, character
+// classes and repetition counts, along with the characters around them
+fn literal_runs(pattern: &str) -> Vec<Run> {
+    let chars: Vec<char> = pattern.chars().collect();
+    let mut runs = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            '\\' => i += 2,
+            '[' => {
+                i += 1;
+                while i < chars.len() && chars[i] != ']' {
+                    i += if chars[i] == '\\' { 2 } else { 1 };
+                }
+                i += 1;
+            }
+            '{' => {
+                while i < chars.len() && chars[i] != '}' {
+                    i += 1;
+                }
+                i += 1;
+            }
+            c if c.is_alphanumeric() || c == '_' => {
+                let start = i;
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                runs.push(Run {
+                    text: chars[start..i].iter().collect(),
+                    start,
+                    end: i,
+                    before: if start > 0 { Some(chars[start - 1]) } else { None },
+                    after: chars.get(i).cloned(),
+                });
+            }
+            _ => i += 1,
+        }
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::test_assets;
+
+    fn assets() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("assets")
+    }
+
+    // A copy of the assets with `palette.json` rewritten by `edit`
+    fn edited_assets(name: &str, edit: impl Fn(String) -> String) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+        for entry in fs::read_dir(assets()).unwrap() {
+            let path = entry.unwrap().path();
+            let target = dir.join(path.file_name().unwrap());
+            if path.is_dir() {
+                fs::create_dir_all(&target).unwrap();
+                for file in fs::read_dir(&path).unwrap() {
+                    let file = file.unwrap().path();
+                    fs::copy(&file, target.join(file.file_name().unwrap())).unwrap();
+                }
+            } else {
+                fs::create_dir_all(&dir).unwrap();
+                fs::copy(&path, &target).unwrap();
+            }
+        }
+        let palette = fs::read_to_string(dir.join("palette.json")).unwrap();
+        fs::write(dir.join("palette.json"), edit(palette)).unwrap();
+        dir
+    }
+
+    // Problems `lint` finds with the rules of an `extra` file layered over the
+    // small test assets
+    fn problems(name: &str, extra: &str) -> Vec<String> {
+        let dir = test_assets::small(name);
+        fs::write(dir.join("extra"), extra).unwrap();
+        let generator = Generator::new(&dir).with_probability_file(&dir.join("extra")).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        lint(&generator).iter().map(|p| p.to_string()).collect()
+    }
+
+    #[test]
+    fn small_assets_have_no_problems() {
+        assert_eq!(problems("lint_clean", ""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn shadowed_rules_are_reported_with_their_file() {
+        assert_eq!(
+            problems("lint_shadowed", ".*option:scar$|0.2\n"),
+            vec!["probabilities line 3: `.*option:scar$` never fires, its paths are all matched first by extra line 1 `.*option:scar$`"]
+        );
+    }
+
+    #[test]
+    fn rules_matching_no_path_are_reported() {
+        assert_eq!(problems("lint_no_path", "^skull:.*frame$|0.5\n"), vec!["extra line 1: `^skull:.*frame$` matches no path the generator looks up"]);
+    }
+
+    #[test]
+    fn unknown_names_are_reported() {
+        let problems = problems("lint_names", ":skull:square$|0.5\n.*:beard$|0.5\n:beard:.*:skull$|0.5\n");
+        assert!(problems.contains(&"extra line 1: unknown skull variant 'square'".to_string()));
+        assert!(problems.contains(&"extra line 2: 'beard' doesn't end any template, variant or option name".to_string()));
+        assert!(problems.contains(&"extra line 3: 'beard' doesn't name anything in the assets".to_string()));
+    }
+
+    #[test]
+    fn unknown_options_are_reported() {
+        let problems = problems("lint_options", ".*:option:sparkles$|0.5\n");
+        assert_eq!(problems[0], "extra line 1: unknown option 'sparkles'");
+    }
+
+    #[test]
+    fn probabilities_above_one_are_reported() {
+        let problems = problems("lint_above_one", ".*skull:long$|1.5\n.*skull:round$|*1.5\n");
+        assert!(problems.contains(&"extra line 1: probability 1.5 is above 1.0".to_string()));
+        assert!(!problems.iter().any(|p| p.starts_with("extra line 2")));
+    }
+
+    #[test]
+    fn shipped_palette_has_no_problems() {
+        assert_eq!(palette_problems(&Generator::new(&assets())), Vec::<String>::new());
+    }
+
+    #[test]
+    fn unknown_classes_are_linted_not_fatal() {
+        let dir = edited_assets("unknown_classes", |palette| palette.replace("\"clothing_main_color\"", "\"clothing_color\""));
+        let generator = Generator::new(&dir);
+        fs::remove_dir_all(&dir).unwrap();
+        let problems = palette_problems(&generator);
+        assert!(problems.contains(&"shirt/jerkin: class 'clothing_main_color' has no section, tone or outline".to_string()));
+        assert!(problems.contains(&"palette section 'clothing_color' isn't used by any template".to_string()));
+    }
+}
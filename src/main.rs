@@ -35,7 +35,10 @@ fn main() {
     svg.set_attribute(Attribute::new(AttributeId::Width, AttributeValue::Number(total_width)));
     svg.set_attribute(Attribute::new(AttributeId::Height, AttributeValue::Number(total_height)));
 
-    let faces: Vec<Document> = (0..x_count * y_count).map(|_| generator.generate(&HashMap::new()).0).collect();
+    let faces: Vec<Document> = (0..x_count * y_count).map(|_| generator.generate(&HashMap::new()).unwrap().0).collect();
+    for (i, face) in faces.iter().enumerate() {
+        face_generator::template::prefix_ids(face, &format!("face{}_", i));
+    }
 
     for x in 0..x_count {
         for y in 0..y_count {
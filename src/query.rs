@@ -0,0 +1,86 @@
+use regex::Regex;
+
+// One `;` separated clause of a query such as
+// `species=dwarf,elf; hair in {bob,crew}; beard=none; eye_color!=blue`
+#[derive(Clone, Debug, PartialEq)]
+pub struct Clause {
+    pub name: String,
+    pub negated: bool,
+    pub values: Vec<String>,
+}
+
+pub fn parse(query: &str) -> Result<Vec<Clause>, String> {
+    let set_re = Regex::new(r"^(?P<name>[^\s=!]+)\s+(?P<not>not\s+)?in\s*\{(?P<values>[^}]*)\}$").unwrap();
+    let eq_re = Regex::new(r"^(?P<name>[^\s=!]+)\s*(?P<not>!)?=\s*(?P<values>.*)$").unwrap();
+
+    let mut clauses = Vec::new();
+    for raw in query.split(';').map(|c| c.trim()).filter(|c| !c.is_empty()) {
+        let caps = match set_re.captures(raw).or_else(|| eq_re.captures(raw)) {
+            Some(caps) => caps,
+            None => return Err(format!("can't parse '{}'", raw)),
+        };
+        let values: Vec<String> = caps["values"]
+            .split(',')
+            .map(|v| v.trim().to_string())
+            .filter(|v| !v.is_empty())
+            .collect();
+        if values.is_empty() {
+            return Err(format!("no values in '{}'", raw));
+        }
+        clauses.push(Clause {
+            name: caps["name"].to_string(),
+            negated: caps.name("not").is_some(),
+            values,
+        });
+    }
+    Ok(clauses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clause(name: &str, negated: bool, values: &[&str]) -> Clause {
+        Clause { name: name.to_string(), negated, values: values.iter().map(|v| v.to_string()).collect() }
+    }
+
+    #[test]
+    fn equality_and_sets() {
+        assert_eq!(
+            parse("species=dwarf,elf; hair in {bob, crew}; beard = none"),
+            Ok(vec![
+                clause("species", false, &["dwarf", "elf"]),
+                clause("hair", false, &["bob", "crew"]),
+                clause("beard", false, &["none"]),
+            ])
+        );
+    }
+
+    #[test]
+    fn negation() {
+        assert_eq!(
+            parse("eye_color!=blue; hat not in {conical,tricorn}"),
+            Ok(vec![clause("eye_color", true, &["blue"]), clause("hat", true, &["conical", "tricorn"])])
+        );
+    }
+
+    #[test]
+    fn empty_clauses_are_skipped() {
+        assert_eq!(parse(""), Ok(vec![]));
+        assert_eq!(parse(" ; beard=none;; "), Ok(vec![clause("beard", false, &["none"])]));
+    }
+
+    #[test]
+    fn empty_values() {
+        assert_eq!(parse("hair in {}"), Err("no values in 'hair in {}'".to_string()));
+        assert_eq!(parse("beard="), Err("no values in 'beard='".to_string()));
+        assert_eq!(parse("hair in { , }"), Err("no values in 'hair in { , }'".to_string()));
+    }
+
+    #[test]
+    fn unparseable_clauses() {
+        assert_eq!(parse("beard"), Err("can't parse 'beard'".to_string()));
+        assert_eq!(parse("hair in bob"), Err("can't parse 'hair in bob'".to_string()));
+        assert!(parse("=bob").is_err());
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+
+use rand::Rng;
+
+use super::color_scheme::ResolvedPalette;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct PaletteChoice {
+    pub variant: String,
+    // Linear RGB components of the sampled color, before any derived tones
+    pub color: [f32; 3],
+    // Tones sampled from a color function of their own, the rest are derived
+    // from `color` when rendering
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub tones: BTreeMap<String, [f32; 3]>,
+}
+
+// Every decision made while generating a face. Rendering a recipe replays
+// these decisions instead of drawing new ones, so a recipe always produces
+// the same SVG for the same asset set.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct FaceRecipe {
+    pub attributes: BTreeMap<String, String>,
+    #[serde(default)]
+    pub palette: BTreeMap<String, PaletteChoice>,
+    // Keyed by guide name, `None` when the guide was left empty
+    #[serde(default)]
+    pub templates: BTreeMap<String, Option<String>>,
+    #[serde(default)]
+    pub options: BTreeMap<String, bool>,
+    // Sampled width, height, top and bottom adjustments keyed by guide name
+    // and variant like `templates`
+    #[serde(default)]
+    pub jitter: BTreeMap<String, [f64; 4]>,
+    // Theme from themes.json the colors are restyled with when rendering. It
+    // doesn't change any decision, so the same face can be rendered in any
+    // theme.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub theme: Option<String>,
+    // The variants and final colors the face was drawn with, filled in when
+    // rendering. Replaying a recipe ignores it.
+    #[serde(default)]
+    pub resolved_palette: ResolvedPalette,
+}
+
+impl FaceRecipe {
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn from_json(json: &str) -> serde_json::Result<Self> {
+        serde_json::from_str(json)
+    }
+
+    // Takes each decision from one of the two parents at random. Attributes and
+    // theme are left empty for the caller to fill in.
+    pub fn inherit<R: Rng>(a: &FaceRecipe, b: &FaceRecipe, rng: &mut R) -> FaceRecipe {
+        FaceRecipe {
+            attributes: BTreeMap::new(),
+            palette: inherit(&a.palette, &b.palette, rng),
+            templates: inherit(&a.templates, &b.templates, rng),
+            options: inherit(&a.options, &b.options, rng),
+            jitter: inherit(&a.jitter, &b.jitter, rng),
+            theme: None,
+            resolved_palette: ResolvedPalette::default(),
+        }
+    }
+
+    // Drops each template, option, palette and jitter decision with probability
+    // `rate` so that rendering the result re-rolls just those. Attributes and
+    // theme are kept.
+    pub fn forget_decisions<R: Rng>(&self, rate: f32, rng: &mut R) -> FaceRecipe {
+        FaceRecipe {
+            attributes: self.attributes.clone(),
+            palette: forget(&self.palette, rate, rng),
+            templates: forget(&self.templates, rate, rng),
+            options: forget(&self.options, rate, rng),
+            jitter: forget(&self.jitter, rate, rng),
+            theme: self.theme.clone(),
+            resolved_palette: ResolvedPalette::default(),
+        }
+    }
+}
+
+fn forget<V: Clone, R: Rng>(decisions: &BTreeMap<String, V>, rate: f32, rng: &mut R) -> BTreeMap<String, V> {
+    decisions
+        .iter()
+        .filter(|_| rng.gen::<f32>() >= rate)
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+fn inherit<V: Clone, R: Rng>(a: &BTreeMap<String, V>, b: &BTreeMap<String, V>, rng: &mut R) -> BTreeMap<String, V> {
+    let mut keys: Vec<&String> = a.keys().chain(b.keys()).collect();
+    keys.sort();
+    keys.dedup();
+    // A key only one parent has comes from that parent whichever is picked
+    keys.into_iter()
+        .filter_map(|k| {
+            let (first, second) = if rng.gen() { (a, b) } else { (b, a) };
+            first.get(k).or_else(|| second.get(k)).map(|v| (k.clone(), v.clone()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+    use rand::rngs::StdRng;
+
+    use super::*;
+
+    fn recipe(templates: &[(&str, Option<&str>)], options: &[(&str, bool)]) -> FaceRecipe {
+        let mut recipe = FaceRecipe::default();
+        recipe.attributes.insert("species".to_string(), "elf".to_string());
+        recipe.theme = Some("sepia".to_string());
+        for (guide, variant) in templates {
+            recipe.templates.insert(guide.to_string(), variant.map(|v| v.to_string()));
+        }
+        for (option, used) in options {
+            recipe.options.insert(option.to_string(), *used);
+        }
+        recipe.jitter.insert("nose".to_string(), [0.0, 0.1, 0.0, 0.2]);
+        recipe
+    }
+
+    #[test]
+    fn forgetting_keeps_attributes_and_theme() {
+        let face = recipe(&[("hair", Some("bob")), ("hat", None), ("nose", Some("1"))], &[("freckles", true), ("stubble", false)]);
+        let mut rng = StdRng::seed_from_u64(1);
+        assert_eq!(face.forget_decisions(0.0, &mut rng), face);
+        let forgotten = face.forget_decisions(1.0, &mut rng);
+        assert_eq!((&forgotten.attributes, &forgotten.theme), (&face.attributes, &face.theme));
+        assert!(forgotten.templates.is_empty() && forgotten.options.is_empty() && forgotten.jitter.is_empty());
+    }
+
+    #[test]
+    fn forgetting_drops_about_the_rate() {
+        let guides: Vec<String> = (0..1000).map(|i| format!("guide_{}", i)).collect();
+        let templates: Vec<(&str, Option<&str>)> = guides.iter().map(|g| (g.as_str(), Some("1"))).collect();
+        let face = recipe(&templates, &[]);
+        let forgotten = face.forget_decisions(0.25, &mut StdRng::seed_from_u64(2));
+        assert!(forgotten.templates.len() > 700 && forgotten.templates.len() < 800);
+        assert!(forgotten.templates.iter().all(|(guide, variant)| face.templates[guide] == *variant));
+    }
+
+    #[test]
+    fn children_take_every_decision_from_a_parent() {
+        let a = recipe(&[("hair", Some("bob")), ("hat", None)], &[("freckles", true)]);
+        let b = recipe(&[("hair", Some("mohawk")), ("nose", Some("2"))], &[("stubble", false)]);
+        for seed in 0..20 {
+            let child = FaceRecipe::inherit(&a, &b, &mut StdRng::seed_from_u64(seed));
+            assert!(child.templates["hair"] == a.templates["hair"] || child.templates["hair"] == b.templates["hair"]);
+            assert_eq!(child.templates["hat"], None);
+            assert_eq!(child.templates["nose"], Some("2".to_string()));
+            assert_eq!((child.options["freckles"], child.options["stubble"]), (true, false));
+            assert_eq!(child.jitter["nose"], a.jitter["nose"]);
+            assert!(child.attributes.is_empty() && child.theme.is_none());
+        }
+    }
+}
@@ -1,5 +1,4 @@
-use rand::prelude::*;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::iter::FromIterator;
 use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
@@ -16,6 +15,7 @@ use resvg::svgdom::{
 };
 use resvg::usvg;
 
+use super::color_scheme::{Gradient, GradientShape, Paint};
 use super::{GenerationContext, Guide, Palette};
 
 pub struct Template {
@@ -23,6 +23,7 @@ pub struct Template {
     optional_nodes: Vec<(String, usize)>,
     contents: Document,
     outer_guide: Option<Guide>,
+    classes: BTreeSet<String>,
 }
 
 impl Template {
@@ -41,12 +42,7 @@ impl Template {
                     let vidx = feature_name.rfind(':').unwrap_or(feature_name.len());
                     let variant = feature_name[vidx..].to_owned();
                     let feature_name = feature_name[..vidx].to_owned();
-                    let mut guide = Guide::new(&node);
-                    if feature_name == "skull" {
-                        fiddle_guide(&mut guide, (-0.1, 0.1), (-0.15, 0.08), (-0.15, 0.15), (0.0, 0.0));
-                    } else if feature_name == "nose" {
-                        fiddle_guide(&mut guide, (0.0, 0.0), (-0.05, 0.7), (0.0, 0.0), (-0.08, 0.25));
-                    }
+                    let guide = Guide::new(&node);
                     guides.push((feature_name, variant, guide, i));
                 } else if id.starts_with("option_") {
                     let re = Regex::new(r"option_(?P<name>[^:-]+)").unwrap();
@@ -61,9 +57,25 @@ impl Template {
             contents: doc,
             optional_nodes,
             outer_guide,
+            classes: BTreeSet::new(),
         }
     }
 
+    // Guide names with their variant suffix, in the order they are filled
+    pub fn guide_names(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.guides.iter().map(|(name, variant, _, _)| (name.as_str(), variant.as_str()))
+    }
+
+    pub fn option_names(&self) -> impl Iterator<Item = &str> {
+        self.optional_nodes.iter().map(|(name, _)| name.as_str())
+    }
+
+    // The CSS classes in the template's SVG, each of which names a palette
+    // color
+    pub fn classes(&self) -> impl Iterator<Item = &str> {
+        self.classes.iter().map(|class| class.as_str())
+    }
+
     pub fn from_directory(path: &Path) -> HashMap<String, Self> {
         let mut results = HashMap::new();
         for entry in path.read_dir().unwrap() {
@@ -102,7 +114,7 @@ impl Template {
             let attributes = node.attributes();
             let class_str = attributes.get_value(AttributeId::Class);
             if let Some(AttributeValue::String(class_str)) = class_str {
-                for class in class_str.split(' ') {
+                for class in class_str.split(' ').filter(|c| !c.is_empty()) {
                     classes.insert(class.to_string());
                 }
             }
@@ -151,7 +163,9 @@ impl Template {
                 }
             }
         }
-        Self::new(&template_node.unwrap(), guide)
+        let mut template = Self::new(&template_node.unwrap(), guide);
+        template.classes = classes.into_iter().collect();
+        template
     }
 
     pub fn generate_from_context(&self, context: &GenerationContext, path: &str) -> Document {
@@ -179,11 +193,13 @@ impl Template {
                 &mut contents,
                 &Guide::new(&node),
                 context.palette,
+                &mut doc,
             );
             node.insert_after(contents);
             node.detach();
         }
         svg.append(main_node);
+        collect_gradients(&mut svg, &mut doc);
         doc.root().append(svg);
         doc
     }
@@ -199,6 +215,11 @@ impl Template {
         }
 
         for (name, name_variant, guide, node_idx) in &self.guides {
+            let mut guide = *guide;
+            if let Some(ranges) = jitter_ranges(name) {
+                fiddle_guide(&mut guide, context.jitter(name, name_variant, &ranges));
+            }
+            let guide = &guide;
             let sub_template = context.choose_template(path, name, name_variant);
             if let Some((sub_template, child_path)) = sub_template {
                 let mut contents = sub_template.rec_generate_from_context(context, &child_path, non_distort_nodes, doc);
@@ -209,6 +230,7 @@ impl Template {
                         &mut contents,
                         guide,
                         context.palette,
+                        doc,
                     );
                     nodes[*node_idx].insert_after(contents);
                     nodes[*node_idx].detach();
@@ -229,8 +251,9 @@ impl Template {
         node: &mut Node,
         target: &Guide,
         palette: &Palette,
+        doc: &mut Document,
     ) {
-        apply_palette(node, palette);
+        apply_palette(node, palette, doc);
         match self.outer_guide.unwrap() {
             Guide::QuadGuide {
                 ax,
@@ -314,7 +337,7 @@ impl Template {
     }
 }
 
-fn apply_palette(root: &mut Node, palette: &Palette) {
+fn apply_palette(root: &mut Node, palette: &Palette, doc: &mut Document) {
     let palette_placeholders:HashMap<String, String> =
         HashMap::from_iter(palette.keys().map(|k| {
             let mut s = DefaultHasher::new();
@@ -323,15 +346,21 @@ fn apply_palette(root: &mut Node, palette: &Palette) {
             (format!("#{:x}", h)[..7].to_string(), k.to_string())
         }));
 
+    // Shapes painted with each gradient and the attribute that paints them
+    let mut gradient_shapes: BTreeMap<String, Vec<(Node, AttributeId)>> = BTreeMap::new();
     for mut node in root.descendants() {
+        let shape = node.clone();
         let mut attrs = node.attributes_mut();
         for aid in &[AttributeId::Fill, AttributeId::Stroke] {
             if let Some(a) = attrs.get_mut(*aid) {
                 let mut new_value = None;
                 if let AttributeValue::Color(c) = a.value {
                     let as_text = c.to_string();
-                    if palette_placeholders.contains_key(&as_text) {
-                        new_value = Some(palette[&palette_placeholders[&as_text]].to_string());
+                    if let Some(class) = palette_placeholders.get(&as_text) {
+                        match &palette[class] {
+                            Paint::Color(color) => new_value = Some(color.to_string()),
+                            Paint::Gradient(_) => gradient_shapes.entry(class.to_string()).or_insert_with(Vec::new).push((shape.clone(), *aid)),
+                        }
                     }
                 }
                 if new_value.is_some() {
@@ -340,6 +369,149 @@ fn apply_palette(root: &mut Node, palette: &Palette) {
             }
         }
     }
+
+    // The gradients go in a <defs> inside the feature so that aligning it
+    // moves them along with the shapes
+    let mut defs = None;
+    for (class, shapes) in gradient_shapes {
+        let gradient = match &palette[&class] {
+            Paint::Gradient(gradient) => gradient,
+            Paint::Color(_) => unreachable!(),
+        };
+        let element = gradient_element(&class, gradient, bounding_box(&shapes), doc);
+        for (mut shape, aid) in shapes {
+            shape.set_attribute(Attribute::new(aid, AttributeValue::Paint(element.clone(), None)));
+        }
+        defs.get_or_insert_with(|| {
+            let defs = doc.create_element(ElementId::Defs);
+            root.append(defs.clone());
+            defs
+        }).append(element);
+    }
+}
+
+// The box around the paths of `shapes`, counting curve control points
+fn bounding_box(shapes: &[(Node, AttributeId)]) -> (f64, f64, f64, f64) {
+    let (mut min_x, mut min_y) = (std::f64::INFINITY, std::f64::INFINITY);
+    let (mut max_x, mut max_y) = (std::f64::NEG_INFINITY, std::f64::NEG_INFINITY);
+    let mut include = |x: f64, y: f64| {
+        min_x = min_x.min(x);
+        min_y = min_y.min(y);
+        max_x = max_x.max(x);
+        max_y = max_y.max(y);
+    };
+    for (shape, _) in shapes {
+        for (id, node) in shape.descendants().svg() {
+            if id != ElementId::Path {
+                continue;
+            }
+            let attributes = node.attributes();
+            if let Some(AttributeValue::Path(path)) = attributes.get_value(AttributeId::D) {
+                for seg in path.iter() {
+                    match *seg {
+                        PathSegment::MoveTo { x, y, .. } | PathSegment::LineTo { x, y, .. } => include(x, y),
+                        PathSegment::CurveTo { x1, y1, x2, y2, x, y, .. } => {
+                            include(x1, y1);
+                            include(x2, y2);
+                            include(x, y);
+                        }
+                        _ => (),
+                    }
+                }
+            }
+        }
+    }
+    if min_x > max_x {
+        return (0.0, 0.0, 0.0, 0.0);
+    }
+    (min_x, min_y, max_x, max_y)
+}
+
+// A gradient in user space spanning `bounds`, so apply_matrix can transform it
+fn gradient_element(class: &str, gradient: &Gradient, bounds: (f64, f64, f64, f64), doc: &mut Document) -> Node {
+    let (min_x, min_y, max_x, max_y) = bounds;
+    let (width, height) = (max_x - min_x, max_y - min_y);
+    let (mut element, coordinates) = match gradient.shape {
+        GradientShape::Linear { x1, y1, x2, y2 } => (
+            doc.create_element(ElementId::LinearGradient),
+            vec![
+                (AttributeId::X1, min_x + x1 * width),
+                (AttributeId::Y1, min_y + y1 * height),
+                (AttributeId::X2, min_x + x2 * width),
+                (AttributeId::Y2, min_y + y2 * height),
+            ],
+        ),
+        GradientShape::Radial { cx, cy, r } => {
+            // As in SVG, a radius is a fraction of the box's normalized diagonal
+            let diagonal = ((width * width + height * height) / 2.0).sqrt();
+            (
+                doc.create_element(ElementId::RadialGradient),
+                vec![
+                    (AttributeId::Cx, min_x + cx * width),
+                    (AttributeId::Cy, min_y + cy * height),
+                    (AttributeId::R, r * diagonal),
+                ],
+            )
+        }
+    };
+    element.set_id(class);
+    element.set_attribute(Attribute::new(AttributeId::GradientUnits, AttributeValue::String("userSpaceOnUse".to_string())));
+    for (aid, value) in coordinates {
+        element.set_attribute(Attribute::new(aid, AttributeValue::Number(value)));
+    }
+    for stop in &gradient.stops {
+        let mut node = doc.create_element(ElementId::Stop);
+        node.set_attribute(Attribute::new(AttributeId::Offset, AttributeValue::Number(stop.offset)));
+        // svgdom colors have no alpha, so a translucent stop's goes in stop-opacity
+        let (color, alpha) = stop.color.split_at(7);
+        node.set_attribute(Attribute::new(AttributeId::StopColor, AttributeValue::Color(Color::from_str(color).unwrap())));
+        if let Ok(alpha) = u8::from_str_radix(alpha, 16) {
+            node.set_attribute(Attribute::new(AttributeId::StopOpacity, AttributeValue::Number(f64::from(alpha) / 255.0)));
+        }
+        element.append(node);
+    }
+    element
+}
+
+// Moves the gradients made for each feature into one <defs> and gives them
+// ids unique within the face, since a class can be painted in several features
+fn collect_gradients(svg: &mut Node, doc: &mut Document) {
+    let gradients: Vec<Node> = svg.descendants().filter(|n| n.is_tag_name(ElementId::LinearGradient) || n.is_tag_name(ElementId::RadialGradient)).collect();
+    if gradients.is_empty() {
+        return;
+    }
+    let mut defs = doc.create_element(ElementId::Defs);
+    for (i, mut gradient) in gradients.into_iter().enumerate() {
+        let id = format!("{}_{}", gradient.id(), i);
+        gradient.set_id(id);
+        gradient.detach();
+        defs.append(gradient);
+    }
+    let emptied: Vec<Node> = svg.descendants().filter(|n| n.is_tag_name(ElementId::Defs) && !n.has_children()).collect();
+    for mut node in emptied {
+        node.detach();
+    }
+    svg.prepend(defs);
+}
+
+// Ids are only unique within one face, so give every face its own prefix
+// before putting several in one document
+pub fn prefix_ids(face: &Document, prefix: &str) {
+    for mut node in face.root().descendants() {
+        if node.has_id() {
+            let id = format!("{}{}", prefix, node.id());
+            node.set_id(id);
+        }
+    }
+}
+
+fn number_attribute(node: &Node, id: AttributeId) -> f64 {
+    let attributes = node.attributes();
+    let value = match attributes.get_value(id) {
+        Some(AttributeValue::Number(value)) => *value,
+        _ => 0.0,
+    };
+    value
 }
 
 fn mpoint(m: &[f64; 16], x: f64, y: f64) -> (f64, f64) {
@@ -398,6 +570,23 @@ fn apply_matrix(node: &mut Node, matrix: &[f64; 16]) {
                     }
                 }
             }
+        } else if id == ElementId::LinearGradient {
+            let (x1, y1) = mpoint(matrix, number_attribute(&node, AttributeId::X1), number_attribute(&node, AttributeId::Y1));
+            let (x2, y2) = mpoint(matrix, number_attribute(&node, AttributeId::X2), number_attribute(&node, AttributeId::Y2));
+            for (aid, value) in &[(AttributeId::X1, x1), (AttributeId::Y1, y1), (AttributeId::X2, x2), (AttributeId::Y2, y2)] {
+                node.set_attribute(Attribute::new(*aid, AttributeValue::Number(*value)));
+            }
+        } else if id == ElementId::RadialGradient {
+            // A radial gradient stays circular, its radius is stretched by the
+            // average of how far the matrix stretches it along each axis
+            let (cx, cy, r) = (number_attribute(&node, AttributeId::Cx), number_attribute(&node, AttributeId::Cy), number_attribute(&node, AttributeId::R));
+            let (x, y) = mpoint(matrix, cx, cy);
+            let (ax, ay) = mpoint(matrix, cx + r, cy);
+            let (bx, by) = mpoint(matrix, cx, cy + r);
+            let r = ((ax - x).hypot(ay - y) + (bx - x).hypot(by - y)) / 2.0;
+            for (aid, value) in &[(AttributeId::Cx, x), (AttributeId::Cy, y), (AttributeId::R, r)] {
+                node.set_attribute(Attribute::new(*aid, AttributeValue::Number(*value)));
+            }
         }
     }
 }
@@ -509,7 +698,17 @@ fn transform2d(
 }
 
 
-fn fiddle_guide(guide: &mut Guide, width_amount: (f64, f64), height_amount: (f64, f64), top_amount: (f64, f64), bottom_amount: (f64, f64)) {
+fn jitter_ranges(feature_name: &str) -> Option<[(f64, f64); 4]> {
+    // Ranges for the width, height, top and bottom adjustments
+    match feature_name {
+        "skull" => Some([(-0.1, 0.1), (-0.15, 0.08), (-0.15, 0.15), (0.0, 0.0)]),
+        "nose" => Some([(0.0, 0.0), (-0.05, 0.7), (0.0, 0.0), (-0.08, 0.25)]),
+        _ => None,
+    }
+}
+
+fn fiddle_guide(guide: &mut Guide, amounts: [f64; 4]) {
+   let [width_amount, height_amount, top_amount, bottom_amount] = amounts;
    match guide {
        Guide::QuadGuide {
            ax,
@@ -543,9 +742,9 @@ fn fiddle_guide(guide: &mut Guide, width_amount: (f64, f64), height_amount: (f64
            let mut by_x = [0, 1, 2, 3];
            by_x.sort_by_key(|i| (x_for_i(*i) * 10000.0) as i32);
 
-           if width_amount.0 != 0.0 || width_amount.1 != 0.0 {
+           if width_amount != 0.0 {
                let width = x_for_i(by_x[3]) - x_for_i(by_x[0]);
-               let amount = (width * thread_rng().gen_range(width_amount.0, width_amount.1)) / 2.0;
+               let amount = (width * width_amount) / 2.0;
                for (i, a) in &[(0, -amount), (1, -amount), (2, amount), (3, amount)] {
                    match by_x[*i] {
                        0 => *ax += a,
@@ -556,9 +755,9 @@ fn fiddle_guide(guide: &mut Guide, width_amount: (f64, f64), height_amount: (f64
                }
            }
 
-           if height_amount.0 != 0.0 || height_amount.1 != 0.0 {
+           if height_amount != 0.0 {
                let height = y_for_i(by_y[3]) - y_for_i(by_y[0]);
-               let amount = (height * thread_rng().gen_range(height_amount.0, height_amount.1)) / 2.0;
+               let amount = (height * height_amount) / 2.0;
                for (i, a) in &[(0, amount), (1, amount)] {
                    match by_y[*i] {
                        0 => *ay += a,
@@ -569,9 +768,9 @@ fn fiddle_guide(guide: &mut Guide, width_amount: (f64, f64), height_amount: (f64
                }
            }
 
-           if top_amount.0 != 0.0 || top_amount.1 != 0.0 {
+           if top_amount != 0.0 {
                let height = y_for_i(by_y[3]) - y_for_i(by_y[0]);
-               let amount = (height * thread_rng().gen_range(top_amount.0, top_amount.1)) / 2.0;
+               let amount = (height * top_amount) / 2.0;
                for (i, a) in &[(0, amount), (1, -amount)] {
                    match by_y[*i] {
                        0 => *ax += a,
@@ -582,9 +781,9 @@ fn fiddle_guide(guide: &mut Guide, width_amount: (f64, f64), height_amount: (f64
                }
            }
 
-           if bottom_amount.0 != 0.0 || bottom_amount.1 != 0.0 {
+           if bottom_amount != 0.0 {
                let height = y_for_i(by_y[3]) - y_for_i(by_y[0]);
-               let amount = (height * thread_rng().gen_range(bottom_amount.0, bottom_amount.1)) / 2.0;
+               let amount = (height * bottom_amount) / 2.0;
                for (i, a) in &[(2, amount), (3, -amount)] {
                    match by_y[*i] {
                        0 => *ax += a,
@@ -600,3 +799,148 @@ fn fiddle_guide(guide: &mut Guide, width_amount: (f64, f64), height_amount: (f64
        _ => (),
    }
 }
+
+#[cfg(test)]
+mod tests {
+    use resvg::svgdom::Path as SvgPath;
+
+    use super::*;
+    use super::super::color_scheme::GradientStop;
+
+    // The color templates paint `class` with until the palette is applied
+    fn placeholder(class: &str) -> Color {
+        let mut s = DefaultHasher::new();
+        class.hash(&mut s);
+        Color::from_str(&format!("#{:x}", s.finish() as u32)[..7]).unwrap()
+    }
+
+    fn square(doc: &mut Document, x: f64, y: f64, size: f64, class: &str) -> Node {
+        let mut path = doc.create_element(ElementId::Path);
+        let segments = vec![
+            PathSegment::MoveTo { abs: true, x, y },
+            PathSegment::LineTo { abs: true, x: x + size, y },
+            PathSegment::LineTo { abs: true, x: x + size, y: y + size },
+            PathSegment::LineTo { abs: true, x, y: y + size },
+            PathSegment::ClosePath { abs: true },
+        ];
+        path.set_attribute(Attribute::new(AttributeId::D, AttributeValue::Path(SvgPath(segments))));
+        path.set_attribute(Attribute::new(AttributeId::Fill, AttributeValue::Color(placeholder(class))));
+        path
+    }
+
+    fn fade() -> Gradient {
+        Gradient {
+            shape: GradientShape::Linear { x1: 0.0, y1: 0.5, x2: 1.0, y2: 0.5 },
+            stops: vec![
+                GradientStop { offset: 0.0, color: "#ff0000".to_string() },
+                GradientStop { offset: 1.0, color: "#0000ff80".to_string() },
+            ],
+        }
+    }
+
+    fn palette() -> Palette {
+        let mut palette = Palette::new();
+        palette.insert("hair_color_fade".to_string(), Paint::Gradient(fade()));
+        palette.insert("skin_color".to_string(), Paint::Color("#e0b090".to_string()));
+        palette
+    }
+
+    fn fill_gradient(shape: &Node) -> Node {
+        match shape.attributes().get_value(AttributeId::Fill) {
+            Some(AttributeValue::Paint(link, _)) => link.clone(),
+            _ => panic!("the shape isn't painted with a gradient"),
+        }
+    }
+
+    fn color(node: &Node, id: AttributeId) -> Color {
+        match node.attributes().get_value(id) {
+            Some(AttributeValue::Color(color)) => *color,
+            _ => panic!("no color"),
+        }
+    }
+
+    fn coordinates(node: &Node, ids: &[AttributeId]) -> Vec<f64> {
+        ids.iter().map(|id| number_attribute(node, *id)).collect()
+    }
+
+    #[test]
+    fn gradients_are_emitted_with_their_stops() {
+        let mut doc = Document::new();
+        let mut feature = doc.create_element(ElementId::G);
+        let shape = square(&mut doc, 10.0, 20.0, 40.0, "hair_color_fade");
+        let skin = square(&mut doc, 70.0, 20.0, 10.0, "skin_color");
+        feature.append(shape.clone());
+        feature.append(skin.clone());
+        apply_palette(&mut feature, &palette(), &mut doc);
+
+        assert_eq!(color(&skin, AttributeId::Fill), Color::new(0xe0, 0xb0, 0x90));
+        let gradient = fill_gradient(&shape);
+        assert_eq!(*gradient.id(), "hair_color_fade");
+        assert!(gradient.parent().unwrap().is_tag_name(ElementId::Defs));
+        // Fractions of the box around the shape, in user space
+        assert_eq!(coordinates(&gradient, &[AttributeId::X1, AttributeId::Y1, AttributeId::X2, AttributeId::Y2]), vec![10.0, 40.0, 50.0, 40.0]);
+
+        let stops: Vec<Node> = gradient.children().collect();
+        assert_eq!(stops.len(), 2);
+        assert_eq!((number_attribute(&stops[0], AttributeId::Offset), color(&stops[0], AttributeId::StopColor)), (0.0, Color::new(255, 0, 0)));
+        assert!(!stops[0].has_attribute(AttributeId::StopOpacity));
+        assert_eq!((number_attribute(&stops[1], AttributeId::Offset), color(&stops[1], AttributeId::StopColor)), (1.0, Color::new(0, 0, 255)));
+        assert_eq!(number_attribute(&stops[1], AttributeId::StopOpacity), 128.0 / 255.0);
+    }
+
+    #[test]
+    fn gradient_coordinates_follow_the_feature() {
+        // Maps the square (0, 0)-(10, 10) onto (100, 50)-(120, 70)
+        let m = transform2d(0.0, 0.0, 10.0, 0.0, 10.0, 10.0, 0.0, 10.0, 100.0, 50.0, 120.0, 50.0, 120.0, 70.0, 100.0, 70.0);
+        let mut doc = Document::new();
+        let mut feature = doc.create_element(ElementId::G);
+        let bounds = (0.0, 0.0, 10.0, 10.0);
+        let linear = gradient_element("fade", &fade(), bounds, &mut doc);
+        let radial = gradient_element("blush", &Gradient { shape: GradientShape::Radial { cx: 0.5, cy: 0.5, r: 0.5 }, stops: Vec::new() }, bounds, &mut doc);
+        assert_eq!(coordinates(&radial, &[AttributeId::Cx, AttributeId::Cy, AttributeId::R]), vec![5.0, 5.0, 5.0]);
+        feature.append(linear.clone());
+        feature.append(radial.clone());
+        apply_matrix(&mut feature, &m);
+
+        let close = |a: Vec<f64>, b: Vec<f64>| a.iter().zip(&b).all(|(a, b)| (a - b).abs() < 1e-9);
+        assert!(close(coordinates(&linear, &[AttributeId::X1, AttributeId::Y1, AttributeId::X2, AttributeId::Y2]), vec![100.0, 60.0, 120.0, 60.0]));
+        assert!(close(coordinates(&radial, &[AttributeId::Cx, AttributeId::Cy, AttributeId::R]), vec![110.0, 60.0, 10.0]));
+    }
+
+    #[test]
+    fn prefixed_ids_are_unique_across_faces() {
+        let faces: Vec<(Document, Node)> = (0..2)
+            .map(|_| {
+                let mut doc = Document::new();
+                let mut svg = doc.create_element(ElementId::Svg);
+                let mut hair = None;
+                // Two features painted with the same gradient class
+                for name in &["hair", "hair_back"] {
+                    let mut feature = doc.create_element(ElementId::G);
+                    let mut shape = square(&mut doc, 0.0, 0.0, 10.0, "hair_color_fade");
+                    shape.set_id(*name);
+                    feature.append(shape.clone());
+                    apply_palette(&mut feature, &palette(), &mut doc);
+                    svg.append(feature);
+                    hair.get_or_insert(shape);
+                }
+                collect_gradients(&mut svg, &mut doc);
+                doc.root().append(svg);
+                (doc, hair.unwrap())
+            })
+            .collect();
+
+        let ids = |doc: &Document| -> Vec<String> { doc.root().descendants().filter(|n| n.has_id()).map(|n| n.id().clone()).collect() };
+        assert_eq!(ids(&faces[0].0), vec!["hair_color_fade_0", "hair_color_fade_1", "hair", "hair_back"]);
+        for (i, (face, _)) in faces.iter().enumerate() {
+            prefix_ids(face, &format!("face{}_", i));
+        }
+        let mut all: Vec<String> = faces.iter().flat_map(|(face, _)| ids(face)).collect();
+        assert_eq!(all.len(), 8);
+        all.sort();
+        all.dedup();
+        assert_eq!(all.len(), 8);
+        // Shapes link to their gradient node, so the renamed id still resolves
+        assert_eq!(*fill_gradient(&faces[1].1).id(), "face1_hair_color_fade_0");
+    }
+}
@@ -0,0 +1,34 @@
+use std::fs;
+use std::path::PathBuf;
+
+// The weight rules of `small`, one per line
+pub const PROBABILITIES: &str = ".*skull:round$|0.75\n.*skull:long$|0.25\n.*option:scar$|0.5\n.*option:freckles$|0.0\n";
+
+fn template(guides: &[&str], options: &[&str]) -> String {
+    let shape = |id: &str| format!(r#"<path id="{}" d="M 60 40 L 150 40 L 150 120 L 60 120 Z" />"#, id);
+    let contents: Vec<String> = guides.iter().chain(options).map(|id| shape(id)).collect();
+    format!(
+        r#"<svg xmlns="http://www.w3.org/2000/svg" width="210" height="210" viewBox="0 0 210 210"><g id="guide"><path d="M 0 0 L 210 0 L 210 210 L 0 210 Z" /></g><g id="contents">{}</g></svg>"#,
+        contents.join("")
+    )
+}
+
+// Writes an asset directory with two species, one frame with an optional scar
+// and a skull guide, and two skulls whose freckles never show
+pub fn small(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("{}_{}", name, std::process::id()));
+    let files = vec![
+        ("frame/adult.svg", template(&["guide_skull"], &["option_scar"])),
+        ("skull/round.svg", template(&[], &["option_freckles"])),
+        ("skull/long.svg", template(&[], &["option_freckles"])),
+        ("attributes.json", r#"[["species", {"elf": 1.0, "orc": 1.0}]]"#.to_string()),
+        ("palette.json", "[]".to_string()),
+        ("probabilities", PROBABILITIES.to_string()),
+    ];
+    for (file, contents) in files {
+        let path = dir.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+    dir
+}
@@ -1,46 +1,462 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
 use std::fs::File;
-use std::io::{BufRead, BufReader};
+use std::io::Read;
 use std::path::Path;
 
-use regex::Regex;
+use regex::{Regex, RegexSet};
+
+pub struct Rule {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    pub regex: Regex,
+    pub weight: Weight,
+    pub combine: Combine,
+    pub priority: i32,
+    // File the rule came from, or "override" for runtime overrides
+    pub source: String,
+    pub line: usize,
+}
+
+// How a matching rule combines with the others. The first matching `Override`
+// rule decides the weight as in a plain first-match file, then every matching
+// `Multiply` rule scales it and every matching `Add` rule adds to it.
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Combine {
+    Override,
+    Multiply,
+    Add,
+}
+
+impl Default for Combine {
+    fn default() -> Self {
+        Combine::Override
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(name) = &self.name {
+            write!(f, "{}: ", name)?;
+        }
+        let mode = match (self.combine, self.weight) {
+            (Combine::Multiply, _) => "*",
+            (Combine::Add, _) => "+",
+            _ => "",
+        };
+        if self.source.is_empty() {
+            write!(f, "{}|{}{} (line {})", self.regex, mode, self.weight, self.line)
+        } else {
+            write!(f, "{}|{}{} ({} line {})", self.regex, mode, self.weight, self.source, self.line)
+        }
+    }
+}
+
+// One rule per line of a `.jsonl` rules file, e.g.
+//   {"name": "no_beards", "description": "...", "pattern": ":female:.*beard$", "weight": "never", "priority": 10}
+// `weight` is "always", "never" or a number, `combine` is "override" (the
+// default), "multiply" or "add", and `name`, `description` and `priority` are
+// optional.
+#[derive(Serialize, Deserialize)]
+struct RawRule {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    description: Option<String>,
+    pattern: String,
+    weight: RawWeight,
+    #[serde(default, skip_serializing_if = "is_override")]
+    combine: Combine,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    priority: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RawWeight {
+    Number(f32),
+    Word(String),
+}
+
+fn is_zero(priority: &i32) -> bool {
+    *priority == 0
+}
+
+fn is_override(combine: &Combine) -> bool {
+    *combine == Combine::Override
+}
+
+#[derive(Debug, PartialEq)]
+pub struct RuleError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for RuleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Error for RuleError {}
 
 pub struct Weights {
-    weights: Vec<(Regex, Weight)>,
+    weights: Vec<Rule>,
+    // Every rule's pattern compiled together, so a lookup is one pass over the
+    // path instead of one per rule
+    set: RegexSet,
+    // Whether any rule multiplies or adds, otherwise lookups stop at the first match
+    combining: bool,
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Weight {
     Always,
     Sometimes(f32),
 }
 
+impl fmt::Display for Weight {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Weight::Always => write!(f, "always"),
+            Weight::Sometimes(prob) => write!(f, "{}", prob),
+        }
+    }
+}
+
+impl Weight {
+    // Whether a draw against this weight can come out as `chosen`
+    pub fn allows(&self, chosen: bool) -> bool {
+        match self {
+            Weight::Always => chosen,
+            Weight::Sometimes(prob) => if chosen { *prob > 0.0 } else { *prob < 1.0 },
+        }
+    }
+}
+
 impl Weights {
-    pub fn new(path: &Path) -> Weights {
+    // Files ending in `.jsonl` hold structured rules, anything else is read as
+    // legacy `regex|weight` lines
+    pub fn from_file(path: &Path) -> Result<Weights, RuleError> {
+        let mut text = String::new();
+        File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut text))
+            .map_err(|e| RuleError { line: 0, message: e.to_string() })?;
+        let mut weights = if path.extension().map_or(false, |e| e == "jsonl") {
+            Weights::from_json_lines(&text)?
+        } else {
+            Weights::from_legacy(&text)?
+        };
+        let source = path.file_name().map_or_else(String::new, |f| f.to_string_lossy().to_string());
+        for rule in &mut weights.weights {
+            rule.source = source.clone();
+        }
+        Ok(weights)
+    }
+
+    // Override rules set in code, tried in the order given. Their line numbers
+    // are positions in `overrides`, starting at 1.
+    pub fn from_overrides(overrides: Vec<(String, Weight)>) -> Result<Weights, RuleError> {
+        let mut weights = Vec::with_capacity(overrides.len());
+        for (idx, (pattern, weight)) in overrides.into_iter().enumerate() {
+            let regex = Regex::new(&pattern).map_err(|e| RuleError { line: idx + 1, message: e.to_string() })?;
+            weights.push(Rule {
+                name: None,
+                description: None,
+                regex,
+                weight,
+                combine: Combine::Override,
+                priority: 0,
+                source: "override".to_string(),
+                line: idx + 1,
+            });
+        }
+        Ok(Weights::from_rules(weights))
+    }
+
+    // Stacks `top` over these rules. Its override rules are tried first, and
+    // multiplying and adding rules from every layer still apply.
+    pub fn layer(self, top: Weights) -> Weights {
+        let mut weights = top.weights;
+        weights.extend(self.weights);
+        Weights::from_rules(weights)
+    }
+
+    // Rules are tried from the highest priority down, in file order within a
+    // priority, and the first one matching a path decides its weight.
+    pub fn from_json_lines(text: &str) -> Result<Weights, RuleError> {
+        let mut weights = Vec::new();
+        let mut names = HashSet::new();
+        for (line_idx, line) in text.lines().enumerate() {
+            let line_number = line_idx + 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let error = |message: String| RuleError { line: line_number, message };
+            let raw: RawRule = serde_json::from_str(line).map_err(|e| error(e.to_string()))?;
+            if let Some(name) = &raw.name {
+                if !names.insert(name.to_string()) {
+                    return Err(error(format!("duplicate rule name '{}'", name)));
+                }
+            }
+            let weight = match raw.weight {
+                RawWeight::Word(ref word) if word == "always" => Weight::Always,
+                RawWeight::Word(ref word) if word == "never" => Weight::Sometimes(0.0),
+                RawWeight::Word(word) => return Err(error(format!("weight must be \"always\", \"never\" or a number, not \"{}\"", word))),
+                RawWeight::Number(prob) => parse_number(prob, raw.combine).map_err(error)?,
+            };
+            if weight == Weight::Always && raw.combine != Combine::Override {
+                return Err(error("only override rules can be \"always\"".to_string()));
+            }
+            weights.push(Rule {
+                name: raw.name,
+                description: raw.description,
+                regex: Regex::new(&raw.pattern).map_err(|e| error(e.to_string()))?,
+                weight,
+                combine: raw.combine,
+                priority: raw.priority,
+                source: String::new(),
+                line: line_number,
+            });
+        }
+        weights.sort_by(|a, b| b.priority.cmp(&a.priority));
+        Ok(Weights::from_rules(weights))
+    }
+
+    // Legacy `regex|weight` lines, where `regex|*0.5` multiplies and `regex|+0.1`
+    // adds. Only an added amount may be negative, so subtracting is written
+    // `regex|+-0.1`. A comment line starting with `#` becomes the
+    // description of the rules directly under it, up to the next blank line.
+    pub fn from_legacy(text: &str) -> Result<Weights, RuleError> {
         let mut weights = Vec::with_capacity(100);
-        let file = File::open(path).unwrap();
-        for line in BufReader::new(file).lines() {
-            let line = line.unwrap();
-            if !line.starts_with('#') & (line != "") {
-                let prob_idx = line.rfind('|').expect("Pattern must have a probability");
-                let re = Regex::new(&line[..prob_idx]).unwrap();
+        let mut description = None;
+        for (line_idx, line) in text.lines().enumerate() {
+            let line_number = line_idx + 1;
+            let error = |message: String| RuleError { line: line_number, message };
+            if line.starts_with('#') {
+                description = Some(line.trim_start_matches('#').trim().to_string());
+            } else if line.trim().is_empty() {
+                description = None;
+            } else {
+                let prob_idx = line.rfind('|').ok_or_else(|| error("rule must end with |probability".to_string()))?;
+                let re = Regex::new(&line[..prob_idx]).map_err(|e| error(e.to_string()))?;
                 let raw_prob = &line[prob_idx + 1..];
+                let (combine, number) = if raw_prob.starts_with('*') {
+                    (Combine::Multiply, &raw_prob[1..])
+                } else if raw_prob.starts_with('+') {
+                    (Combine::Add, &raw_prob[1..])
+                } else {
+                    (Combine::Override, raw_prob)
+                };
                 let prob = if raw_prob == "always" {
                     Weight::Always
                 } else {
-                    Weight::Sometimes(raw_prob.parse::<f32>().unwrap())
+                    let prob = number.parse::<f32>().map_err(|_| error(format!("invalid probability '{}'", raw_prob)))?;
+                    parse_number(prob, combine).map_err(error)?
                 };
-                weights.push((re, prob))
+                weights.push(Rule {
+                    name: None,
+                    description: description.clone(),
+                    regex: re,
+                    weight: prob,
+                    combine,
+                    priority: 0,
+                    source: String::new(),
+                    line: line_number,
+                })
             }
         }
-        Weights { weights }
+        Ok(Weights::from_rules(weights))
+    }
+
+    fn from_rules(weights: Vec<Rule>) -> Weights {
+        let combining = weights.iter().any(|rule| rule.combine != Combine::Override);
+        // Every pattern already compiled on its own
+        let set = RegexSet::new(weights.iter().map(|rule| rule.regex.as_str())).unwrap();
+        Weights { weights, set, combining }
+    }
+
+    // The rules in the structured format, one per line in the order they are tried
+    pub fn to_json_lines(&self) -> String {
+        let mut text = String::new();
+        for rule in &self.weights {
+            let weight = match rule.weight {
+                Weight::Always => RawWeight::Word("always".to_string()),
+                Weight::Sometimes(prob) if prob == 0.0 && rule.combine != Combine::Add => RawWeight::Word("never".to_string()),
+                Weight::Sometimes(prob) => RawWeight::Number(prob),
+            };
+            let raw = RawRule {
+                name: rule.name.clone(),
+                description: rule.description.clone(),
+                pattern: rule.regex.as_str().to_string(),
+                weight,
+                combine: rule.combine,
+                priority: rule.priority,
+            };
+            text.push_str(&serde_json::to_string(&raw).unwrap());
+            text.push('\n');
+        }
+        text
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.weights
+    }
+
+    // The override rule that decides the weight for `path`
+    pub fn rule_for_path(&self, path: &str) -> Option<&Rule> {
+        self.matching(path).find(|rule| rule.combine == Combine::Override)
+    }
+
+    // Every rule matching `path`, in the order they are tried
+    fn matching<'a>(&'a self, path: &str) -> impl Iterator<Item = &'a Rule> {
+        self.set.matches(path).into_iter().map(move |i| &self.weights[i])
+    }
+
+    // Every rule that contributes to the weight for `path`, in the order they are tried
+    pub fn rules_for_path(&self, path: &str) -> Vec<&Rule> {
+        let mut decided = false;
+        let mut rules = Vec::new();
+        for rule in self.matching(path) {
+            if rule.combine != Combine::Override || !decided {
+                decided |= rule.combine == Combine::Override;
+                rules.push(rule);
+                if decided && !self.combining {
+                    break;
+                }
+            }
+        }
+        rules
     }
 
     pub fn for_path(&self, path: &str) -> Weight {
-        for (re, prob) in &self.weights {
-            if re.is_match(path) {
-                return *prob;
+        let mut base = None;
+        let mut factor = 1.0;
+        let mut offset = 0.0;
+        for rule in self.rules_for_path(path) {
+            match (rule.combine, rule.weight) {
+                (Combine::Override, weight) => base = Some(weight),
+                (Combine::Multiply, Weight::Sometimes(f)) => factor *= f,
+                (Combine::Add, Weight::Sometimes(a)) => offset += a,
+                (_, Weight::Always) => {}
             }
         }
-        Weight::Sometimes(1.0)
+        let base = base.unwrap_or(Weight::Sometimes(1.0));
+        if factor == 1.0 && offset == 0.0 {
+            return base;
+        }
+        let prob = match base {
+            Weight::Always => 1.0,
+            Weight::Sometimes(prob) => prob,
+        };
+        Weight::Sometimes((prob * factor + offset).max(0.0))
+    }
+}
+
+// Added amounts may be negative, everything else has to be at least 0
+fn parse_number(prob: f32, combine: Combine) -> Result<Weight, String> {
+    if prob.is_finite() && (prob >= 0.0 || combine == Combine::Add) {
+        Ok(Weight::Sometimes(prob))
+    } else {
+        Err(format!("probability must be a number of at least 0, not {}", prob))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error<T>(result: Result<T, RuleError>) -> RuleError {
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e,
+        }
+    }
+
+    #[test]
+    fn legacy_comments_describe_the_rules_under_them() {
+        let weights = Weights::from_legacy("# No bearded ladies\n:female:.*beard$|0.0\n:female:.*goatee$|0.0\n\n.*:hat$|0.5\n").unwrap();
+        let descriptions: Vec<Option<&str>> = weights.rules().iter().map(|r| r.description.as_ref().map(|d| d.as_str())).collect();
+        assert_eq!(descriptions, vec![Some("No bearded ladies"), Some("No bearded ladies"), None]);
+    }
+
+    #[test]
+    fn legacy_combining_rules() {
+        let weights = Weights::from_legacy(".*:hat$|0.5\n:elf:.*:hat$|*0.5\n:dwarf:.*:hat$|+0.25\n:goblin:.*:hat$|+-0.5\n").unwrap();
+        assert_eq!(weights.for_path(":human:head:hat"), Weight::Sometimes(0.5));
+        assert_eq!(weights.for_path(":elf:head:hat"), Weight::Sometimes(0.25));
+        assert_eq!(weights.for_path(":dwarf:head:hat"), Weight::Sometimes(0.75));
+        assert_eq!(weights.for_path(":goblin:head:hat"), Weight::Sometimes(0.0));
+        assert_eq!(weights.rules()[3].to_string(), ":goblin:.*:hat$|+-0.5 (line 4)");
+    }
+
+    #[test]
+    fn legacy_errors_have_line_numbers() {
+        assert_eq!(error(Weights::from_legacy("# ok\n.*|1.0\n(unclosed|0.5\n")).line, 3);
+        let e = error(Weights::from_legacy(".*|1.0\n\n.*:hat$|lots\n"));
+        assert_eq!((e.line, e.message.as_str()), (3, "invalid probability 'lots'"));
+        assert_eq!(error(Weights::from_legacy(".*:hat$|+-1.0\n.*:hat$|*-1.0\n")).line, 2);
+        let e = error(Weights::from_legacy(".*:hat$|-0.1\n"));
+        assert_eq!((e.line, e.message.as_str()), (1, "probability must be a number of at least 0, not -0.1"));
+        assert_eq!(error(Weights::from_legacy(".*:hat$\n")).line, 1);
+    }
+
+    #[test]
+    fn json_lines_errors_have_line_numbers() {
+        assert_eq!(error(Weights::from_json_lines("{\"pattern\": \"(\", \"weight\": 1}\n")).line, 1);
+        let e = error(Weights::from_json_lines("{\"pattern\": \".*\", \"weight\": 1}\n\n{\"pattern\": \".*\", \"weight\": \"often\"}\n"));
+        assert_eq!(e.line, 3);
+        assert_eq!(error(Weights::from_json_lines("{\"pattern\": \".*\", \"weight\": -0.5}\n")).line, 1);
+        let e = error(Weights::from_json_lines("{\"name\": \"a\", \"pattern\": \".*\", \"weight\": 1}\n{\"name\": \"a\", \"pattern\": \"x\", \"weight\": 1}\n"));
+        assert_eq!((e.line, e.message.as_str()), (2, "duplicate rule name 'a'"));
+        assert_eq!(error(Weights::from_json_lines("{\"pattern\": \".*\", \"weight\": \"always\", \"combine\": \"add\"}\n")).line, 1);
+    }
+
+    #[test]
+    fn weights_can_be_shared_between_threads() {
+        fn shared<T: Send + Sync>(_: &T) {}
+        shared(&Weights::from_legacy(".*:hat$|0.5\n").unwrap());
+    }
+
+    #[test]
+    fn overrides_beat_the_asset_file() {
+        let assets = Weights::from_legacy(".*:hat$|0.5\n:elf:.*:hat$|*0.5\n").unwrap();
+        let weights = assets.layer(Weights::from_overrides(vec![(":elf:.*:hat$".to_string(), Weight::Always)]).unwrap());
+        assert_eq!(weights.for_path(":elf:head:hat"), Weight::Sometimes(0.5));
+        assert_eq!(weights.for_path(":dwarf:head:hat"), Weight::Sometimes(0.5));
+        let sources: Vec<&str> = weights.rules().iter().map(|r| r.source.as_str()).collect();
+        assert_eq!(sources, vec!["override", "", ""]);
+        let weights = Weights::from_legacy(".*:hat$|0.5\n").unwrap().layer(Weights::from_overrides(vec![(".*:hat$".to_string(), Weight::Sometimes(0.8))]).unwrap());
+        assert_eq!(weights.for_path(":elf:head:hat"), Weight::Sometimes(0.8));
+    }
+
+    #[test]
+    fn later_layers_are_tried_first() {
+        let base = Weights::from_legacy(".*:hat$|0.1\n").unwrap();
+        let middle = Weights::from_legacy(":elf:.*:hat$|0.2\n:dwarf:.*:hat$|0.4\n").unwrap();
+        let top = Weights::from_legacy(":elf:.*:hat$|0.3\n").unwrap();
+        let weights = base.layer(middle).layer(top);
+        let order: Vec<&str> = weights.rules().iter().map(|r| r.regex.as_str()).collect();
+        assert_eq!(order, vec![":elf:.*:hat$", ":elf:.*:hat$", ":dwarf:.*:hat$", ".*:hat$"]);
+        assert_eq!(weights.for_path(":elf:head:hat"), Weight::Sometimes(0.3));
+        assert_eq!(weights.for_path(":dwarf:head:hat"), Weight::Sometimes(0.4));
+        assert_eq!(weights.for_path(":human:head:hat"), Weight::Sometimes(0.1));
+    }
+
+    #[test]
+    fn legacy_round_trips_through_json_lines() {
+        let legacy = "# Hats\n.*:hat$|0.5\n:elf:.*:hat$|*0.5\n\n:child.*beard$|0.0\nhuman:.*skull:human[^:]*$|always\n:goblin:.*:hat$|+-0.25\n";
+        let weights = Weights::from_legacy(legacy).unwrap();
+        let json = weights.to_json_lines();
+        let loaded = Weights::from_json_lines(&json).unwrap();
+        assert_eq!(loaded.to_json_lines(), json);
+        for (a, b) in weights.rules().iter().zip(loaded.rules()) {
+            assert_eq!((a.regex.as_str(), a.weight, a.combine, &a.description), (b.regex.as_str(), b.weight, b.combine, &b.description));
+        }
+        for path in &[":elf:head:hat", ":goblin:head:hat", ":child:beard", "human:skull:human", ":human:nose"] {
+            assert_eq!(weights.for_path(path), loaded.for_path(path));
+        }
     }
 }